@@ -1,8 +1,9 @@
-use std::path::PathBuf;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 
-use anyhow::{bail, Ok};
+use anyhow::{bail, Context, Ok};
 use clap::Parser;
 use image::{DynamicImage, GenericImageView};
 use log::{debug, info, warn};
@@ -11,17 +12,25 @@ use winit::event::{Event, KeyEvent, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::keyboard::{Key, NamedKey};
 use winit::window::WindowBuilder;
-use yahallo::camera::Cam;
+use yahallo::camera::emitter::UvcDevice;
+use yahallo::camera::{self, FrameSource};
 use yahallo::config::Config;
+use yahallo::spoof::SpoofFeatures;
 use yahallo::{
-    img_to_dlib, is_dark, process_image, resize_to_width, to_rgb, FaceRecognizer, ImageMatrix,
-    Rectangle,
+    img_to_dlib, is_dark, mean_brightness, process_image, resize_to_width, scale_rect, to_rgb,
+    DlibImage, FaceRecognizer, Rectangle,
 };
 
 #[derive(Debug, Parser, Clone)]
 #[command(name = "yahallo")]
 #[command(about = "Facial Recognition CLI", long_about = None)]
 struct Cli {
+    /// Config file to use instead of /etc/yahallo/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Models dir to use instead of the one in the config
+    #[arg(long, global = true)]
+    models_dir: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,6 +39,9 @@ struct Cli {
 enum Commands {
     // #[command(arg_required_else_help = true)]
     Add {
+        /// User the face belongs to. Defaults to the user running sudo, or the current one.
+        #[arg(long)]
+        user: Option<String>,
         #[arg(long)]
         label: Option<String>,
         /// How long to wait for face
@@ -45,6 +57,56 @@ enum Commands {
         #[arg(long)]
         timeout: Option<humantime::Duration>,
     },
+    /// Record frames from the camera, to be replayed later
+    Record {
+        /// Number of frames to record
+        #[arg(long)]
+        frames: usize,
+        /// Directory to write the session into.
+        /// Set it as `camera` in the config to replay it.
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// List the cameras on the system, and the formats they support
+    Cameras,
+    /// Find the UVC control that turns on the IR emitter of the camera.
+    ///
+    /// Tries setting each extension unit control of the first camera in the config to
+    /// its maximum, and checks if the frames get brighter. The controls are restored
+    /// afterwards. Writing to unknown controls is not guaranteed to be harmless, use at
+    /// your own risk.
+    ProbeEmitter {
+        /// Write the working control to this config file,
+        /// like /etc/yahallo/config.d/50-emitter.toml
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+    /// Move the faces from the single faces file of older versions into the models dir
+    Migrate {
+        /// Faces file to read, instead of `faces_file` from the config
+        #[arg(long)]
+        from: Option<PathBuf>,
+        /// User to give the faces that don't belong to anyone yet
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Inspect the config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum ConfigCommands {
+    /// Print the effective config, along with where each value came from
+    Show {
+        /// Show the config with the overrides for this user applied
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Check that the config is valid and its paths are usable
+    Check,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -52,19 +114,181 @@ fn main() -> anyhow::Result<()> {
         .filter_level(log::LevelFilter::Debug)
         .init();
     let args = Cli::parse();
-    let config = Config::new(
-        PathBuf::from("/dev/video2"),
-        PathBuf::from("data"),
-        PathBuf::from("data/faces.json"),
-        0.6,
-        30,
-    )?;
+    let mut config = Config::load_from(args.config.as_deref())?;
+    if let Some(models_dir) = args.models_dir {
+        config.set_models_dir(models_dir)?;
+    }
     match args.command {
-        Commands::Add { label, timeout } => handle_add(config, timeout.into(), label)?,
+        Commands::Add {
+            user,
+            label,
+            timeout,
+        } => handle_add(config, timeout.into(), user, label)?,
         Commands::Test {
             exit_on_match: _,
             timeout,
         } => handle_test(config, timeout.map(|t| t.into()))?,
+        Commands::Record { frames, out } => handle_record(config, frames, &out)?,
+        Commands::Cameras => handle_cameras()?,
+        Commands::ProbeEmitter { save } => handle_probe_emitter(config, save.as_deref())?,
+        Commands::Migrate { from, user } => handle_migrate(config, from, user.as_deref())?,
+        Commands::Config { command } => handle_config(config, command)?,
+    }
+    Ok(())
+}
+
+fn handle_migrate(config: Config, from: Option<PathBuf>, user: Option<&str>) -> anyhow::Result<()> {
+    let from = from.as_deref().unwrap_or(config.faces_file());
+    let moved = yahallo::data::migrate(from, config.models_dir(), user)?;
+    for (user, count) in moved {
+        println!("Moved {count} faces to {user}");
+    }
+    println!("Renamed {} to {}.migrated", from.display(), from.display());
+    Ok(())
+}
+
+fn handle_record(config: Config, frames: usize, out: &Path) -> anyhow::Result<()> {
+    let (camera, mut cam) = camera::open(&config)?;
+    camera::record(cam.as_mut(), frames, out)?;
+    cam.stop()?;
+    println!(
+        "Recorded {frames} frames from {} to {}",
+        camera.display(),
+        out.display()
+    );
+    Ok(())
+}
+
+fn handle_cameras() -> anyhow::Result<()> {
+    let devices = camera::list_devices()?;
+    if devices.is_empty() {
+        println!("No cameras found");
+    }
+    for dev in devices {
+        println!(
+            "{} {:?}{}",
+            dev.path.display(),
+            dev.name.as_deref().unwrap_or("unknown"),
+            if dev.likely_ir { " (likely IR)" } else { "" }
+        );
+        // the path is already printed above
+        for spec in dev.specs().iter().skip(1) {
+            println!("    camera = {spec:?}");
+        }
+        for fmt in &dev.formats {
+            let resolutions = match &fmt.resolutions {
+                camera::ResolutionInfo::Discretes(v) => v
+                    .iter()
+                    .map(|(w, h)| format!("{w}x{h}"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                camera::ResolutionInfo::Stepwise { min, max, step } => format!(
+                    "{}x{} to {}x{} in steps of {}x{}",
+                    min.0, min.1, max.0, max.1, step.0, step.1
+                ),
+            };
+            println!(
+                "    {} ({}): {resolutions}",
+                camera::format::fourcc_str(&fmt.format),
+                fmt.description
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Frames to skip after changing a control, for it to take effect
+const PROBE_SETTLE_FRAMES: usize = 5;
+/// Frames to average the brightness over
+const PROBE_FRAMES: usize = 5;
+/// How much brighter the frames need to get for the emitter to count as on
+const PROBE_MIN_GAIN: f64 = 16.0;
+
+/// Average brightness of the frames after letting the camera settle
+fn probe_brightness(cam: &mut dyn FrameSource, config: &Config) -> anyhow::Result<f64> {
+    for _ in 0..PROBE_SETTLE_FRAMES {
+        cam.capture()?;
+    }
+    let mut total = 0.0;
+    for _ in 0..PROBE_FRAMES {
+        total += mean_brightness(&process_image(cam.capture()?, config)?);
+    }
+    Ok(total / PROBE_FRAMES as f64)
+}
+
+fn handle_probe_emitter(config: Config, save: Option<&Path>) -> anyhow::Result<()> {
+    if !config.emitter().is_empty() {
+        bail!(
+            "`emitter` is already set in {}, remove it to probe again",
+            config.source("emitter")
+        );
+    }
+    let (path, mut cam) = camera::open(&config)?;
+    let device = UvcDevice::open(&path)?;
+    let baseline = probe_brightness(cam.as_mut(), &config)?;
+    println!("Brightness without emitter: {baseline:.1}");
+    let mut found = None;
+    for (unit, selector) in device.controls() {
+        let (cur, max) = match (
+            device.get_cur(unit, selector),
+            device.get_max(unit, selector),
+        ) {
+            (Result::Ok(cur), Result::Ok(max)) => (cur, max),
+            (Err(e), _) | (_, Err(e)) => {
+                debug!("Skipping: {e:#}");
+                continue;
+            }
+        };
+        if cur == max {
+            continue;
+        }
+        if let Err(e) = device.set_cur(&max) {
+            debug!("Skipping: {e:#}");
+            continue;
+        }
+        let brightness = probe_brightness(cam.as_mut(), &config);
+        device.set_cur(&cur)?;
+        let brightness = brightness?;
+        println!("{max} = {:?}: brightness {brightness:.1}", max.data);
+        if brightness - baseline >= PROBE_MIN_GAIN {
+            found = Some(max);
+            break;
+        }
+    }
+    cam.stop()?;
+    let Some(control) = found else {
+        bail!("None of the controls turned on the emitter");
+    };
+    let snippet = camera::emitter::config_snippet(&[control]);
+    println!("Found the emitter control:\n\n{snippet}");
+    if let Some(save) = save {
+        std::fs::write(save, &snippet)?;
+        println!("Saved to {}", save.display());
+    }
+    Ok(())
+}
+
+fn handle_config(config: Config, command: ConfigCommands) -> anyhow::Result<()> {
+    match command {
+        ConfigCommands::Show { user } => {
+            let config = match user {
+                Some(user) => config.for_user(&user),
+                None => config,
+            };
+            for (key, value, source) in config.explain() {
+                println!("{key} = {value}  # {source}");
+            }
+        }
+        ConfigCommands::Check => {
+            let problems = config.check();
+            for problem in &problems {
+                println!("{problem:#}");
+            }
+            if !problems.is_empty() {
+                bail!("Found {} problem(s) in config", problems.len());
+            }
+            println!("Config OK");
+        }
     }
     Ok(())
 }
@@ -77,7 +301,7 @@ const FONT_DATA: &[u8] = include_bytes!(concat!(
 fn redraw(
     buffer: &mut [u32],
     fr: &FaceRecognizer,
-    cam: &mut Cam,
+    cam: &mut dyn FrameSource,
     config: &Config,
     font_bundle: &FontBundle,
 ) -> anyhow::Result<Instant> {
@@ -86,8 +310,8 @@ fn redraw(
     let next_frame_at = start + cam.interval();
     info!("New frame");
     const WIDTH: f64 = 320.0;
-    let scale = frame.resolution.0 as f64 / WIDTH;
-    let img = process_image(frame)?;
+    let img = process_image(frame, config)?;
+    let scale = img.width() as f64 / WIDTH;
     // first, write the original image to output buffer
     debug_assert_eq!(
         img.width() as usize * img.height() as usize,
@@ -101,41 +325,47 @@ fn redraw(
         info!("frame too dark!");
         return Ok(next_frame_at);
     }
-    let img = to_rgb(&img);
+    let gray = img;
+    let img = to_rgb(&gray);
     let resized = resize_to_width(&img, WIDTH as _);
-    let matrix = ImageMatrix::from_image(&resized);
-    let Some(rect) = fr.get_face_rect(&matrix)? else {
+    let matrix = DlibImage::new(&resized);
+    let Some(rect) = fr.get_face_rect(&matrix, config)? else {
         info!("No face in frame");
         return Ok(next_frame_at);
     };
     let encodings = fr.gen_encodings_with_rect(&matrix, &rect);
     // upscale the rect to orig image size
-    let rect = Rectangle {
-        left: (rect.left as f64 * scale) as i64,
-        top: (rect.top as f64 * scale) as i64,
-        right: (rect.right as f64 * scale) as i64,
-        bottom: (rect.bottom as f64 * scale) as i64,
-    };
+    let rect = scale_rect(&rect, scale);
+    let spoof = SpoofFeatures::from_face(&gray, &rect);
+    if let Some(spoof) = spoof {
+        debug!("Spoof {spoof}");
+    }
     debug!("writing pixels!");
     // draw_rect(buffer, img.width() as _, rect, RED);
     let name;
     if encodings.len() > 1 {
-        name = "Too many!";
-    } else if encodings.len() == 0 {
-        name = "Unknown";
+        name = "Too many!".to_owned();
+    } else if encodings.is_empty() {
+        name = "Unknown".to_owned();
     } else {
         let enc = &encodings[0];
-        if let Some(info) = fr.get_enc_info(enc, config) {
-            name = info.label();
-        } else {
-            name = "Not found"
+        match fr.get_enc_info(enc, None) {
+            Some(res) if FaceRecognizer::is_match(&res, config) => {
+                let user = res.model().user().unwrap_or_default();
+                name = format!("{user} ({:.2})", res.distance());
+            }
+            _ => name = "Not found".to_owned(),
         }
     }
+    let name = format!(
+        "{name} spoof {:.2}",
+        spoof.as_ref().map_or(0.0, SpoofFeatures::score)
+    );
 
     let mut dyn_img = DynamicImage::ImageRgb8(img);
     text_on_image::text_on_image_draw_debug(
         &mut dyn_img,
-        name,
+        &name,
         font_bundle,
         rect.left.try_into()?,
         rect.top.try_into()?,
@@ -202,9 +432,22 @@ fn draw_rect(buffer: &mut [u32], buffer_w: usize, rect: Rectangle, color: image:
     }
 }
 
-fn handle_add(config: Config, timeout: Duration, label: Option<String>) -> anyhow::Result<()> {
+fn handle_add(
+    config: Config,
+    timeout: Duration,
+    user: Option<String>,
+    label: Option<String>,
+) -> anyhow::Result<()> {
+    let user = match user {
+        Some(user) => user,
+        None => std::env::var("SUDO_USER")
+            .or_else(|_| std::env::var("USER"))
+            .context("couldn't tell the current user, pass --user")?,
+    };
+    info!("Adding a face for {user}");
+    let config = config.for_user(&user);
     let mut fr = FaceRecognizer::new(&config)?;
-    let mut cam = Cam::start(config.camera_path())?;
+    let (_, mut cam) = camera::open(&config)?;
     let start = Instant::now();
     loop {
         if start.elapsed() >= timeout {
@@ -212,21 +455,21 @@ fn handle_add(config: Config, timeout: Duration, label: Option<String>) -> anyho
             bail!("No face detected!");
         }
         let frame = cam.capture()?;
-        let img = process_image(frame)?;
+        let img = process_image(frame, &config)?;
         if is_dark(&img, config.dark_threshold()) {
             info!("frame too dark!");
             continue;
         }
         let img = to_rgb(&img);
         let matrix = img_to_dlib(&img)?;
-        let Some(rect) = fr.get_face_rect(&matrix)? else {
+        let Some(rect) = fr.get_face_rect(&matrix, &config)? else {
             info!("No face in frame");
             continue;
         };
         let encodings = fr.gen_encodings_with_rect(&matrix, &rect);
         let encoding = encodings.first().unwrap();
-        fr.add_face(encoding.clone(), label)?;
-        fr.dump_faces_file(config.faces_file())?;
+        fr.add_face(encoding.clone(), user.clone(), label)?;
+        fr.save_faces(&user)?;
         break;
     }
     Ok(())
@@ -237,8 +480,16 @@ fn handle_test(config: Config, timeout: Option<Duration>) -> anyhow::Result<()>
     let font_bundle = text_on_image::FontBundle::new(&font, rusttype::Scale::uniform(30.0), RED);
 
     let fr = FaceRecognizer::new(&config)?;
-    let mut cam = Cam::start(config.camera_path())?;
+    let (_, mut cam) = camera::open(&config)?;
     let (width, height) = cam.resolution()?;
+    // the window shows the frames after they are rotated
+    let (width, height) = config
+        .camera_transform()
+        .apply_dims((width.get(), height.get()));
+    let (width, height) = (
+        NonZeroU32::new(width).unwrap(),
+        NonZeroU32::new(height).unwrap(),
+    );
     let start = Instant::now();
     let event_loop = EventLoop::new().unwrap();
     let window = Rc::new(
@@ -272,14 +523,14 @@ fn handle_test(config: Config, timeout: Option<Duration>) -> anyhow::Result<()>
                 // | Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
                 let mut buffer = surface.buffer_mut().unwrap();
                 // the redraw call is blocking- will be limited by the cam fps
-                let next_frame_at = match redraw(&mut buffer, &fr, &mut cam, &config, &font_bundle)
-                {
-                    Result::Ok(next_frame_at) => next_frame_at,
-                    Err(err) => {
-                        warn!("Failed to draw: {err}");
-                        Instant::now()
-                    }
-                };
+                let next_frame_at =
+                    match redraw(&mut buffer, &fr, cam.as_mut(), &config, &font_bundle) {
+                        Result::Ok(next_frame_at) => next_frame_at,
+                        Err(err) => {
+                            warn!("Failed to draw: {err}");
+                            Instant::now()
+                        }
+                    };
                 buffer.present().unwrap();
                 window.request_redraw();
                 elwt.set_control_flow(ControlFlow::wait_duration(
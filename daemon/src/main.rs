@@ -1,99 +1,340 @@
 //! DBus daemon
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 use dbus::blocking::{stdintf::org_freedesktop_dbus::RequestNameReply, Connection};
+use dbus::channel::MatchingReceiver;
+use dbus::message::MatchRule;
 use dbus_crossroads::{Context, Crossroads};
 
-use anyhow::bail;
-use log::{info, warn};
-use yahallo::{camera::Cam, config::Config, img_to_dlib, process_image, FaceRecognizer};
+use anyhow::{anyhow, bail};
+use clap::Parser;
+use log::{debug, info, warn};
+use yahallo::camera::{CameraSpec, FrameSource};
+use yahallo::consensus::Consensus;
+use yahallo::liveness::BlinkDetector;
+use yahallo::spoof::SpoofFeatures;
+use yahallo::{camera, config::Config, img_to_dlib, process_image, scale_rect, FaceRecognizer};
 use yahallo::{is_dark, to_rgb, DbusResult, Error, YahalloResult};
 
+#[derive(Debug, Clone, Parser)]
+#[command(name = "yahallod")]
+#[command(about = "Facial Recognition Daemon", long_about = None)]
+struct Args {
+    /// Config file to use instead of /etc/yahallo/config.toml
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Models dir to use instead of the one in the config
+    #[arg(long)]
+    models_dir: Option<PathBuf>,
+}
+
+impl Args {
+    fn load_config(&self) -> anyhow::Result<Config> {
+        let mut config = Config::load_from(self.config.as_deref())?;
+        if let Some(models_dir) = &self.models_dir {
+            config.set_models_dir(models_dir.clone())?;
+        }
+        Ok(config)
+    }
+}
+
+/// A camera kept open between requests
+struct WarmCamera {
+    /// The `camera` config it was opened for
+    cameras: Vec<CameraSpec>,
+    path: PathBuf,
+    cam: Box<dyn FrameSource>,
+    last_used: Instant,
+}
+
 struct State {
     fr: FaceRecognizer,
     config: Config,
     cam_drop: Option<JoinHandle<()>>,
+    /// Kept open for `camera_idle_timeout` after a request
+    warm: Option<WarmCamera>,
+    /// Needed to reload the config from the same place
+    args: Args,
 }
 
 impl State {
-    fn myconfig() -> anyhow::Result<Self> {
-        let config = Config::new(
-            PathBuf::from("/dev/video2"),
-            PathBuf::from("data"),
-            PathBuf::from("data/faces.json"),
-            0.6,
-            100,
-        )?;
+    fn myconfig(args: Args) -> anyhow::Result<Self> {
+        let config = args.load_config()?;
         let fr = FaceRecognizer::new(&config)?;
         Ok(Self {
             fr,
             config,
             cam_drop: None,
+            warm: None,
+            args,
         })
     }
+
+    /// Re-read the config. The current state is kept if the new config is invalid.
+    ///
+    /// This validates the config like at startup. Other problems, like an unplugged camera,
+    /// are only logged since they may be gone by the next request.
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let config = self.args.load_config()?;
+        for problem in config.check() {
+            warn!("{problem:#}");
+        }
+        if config.dlib_model_dir() != self.config.dlib_model_dir()
+            || config.blink_liveness() != self.config.blink_liveness()
+        {
+            info!("dlib models changed, reloading them");
+            self.fr = FaceRecognizer::new(&config)?;
+        } else {
+            self.fr.reload_faces(&config)?;
+        }
+        // The camera settings may have changed, the next request opens it afresh
+        self.close_camera();
+        self.config = config;
+        info!("Reloaded config");
+        Ok(())
+    }
+
+    /// Get the warm camera if it is the one from `config`, else open it.
+    fn take_camera(&mut self, config: &Config) -> anyhow::Result<(PathBuf, Box<dyn FrameSource>)> {
+        if let Some(mut warm) = self.warm.take() {
+            if warm.cameras == config.cameras() {
+                debug!("Reusing camera {}", warm.path.display());
+                match warm.cam.flush() {
+                    Ok(()) => return Ok((warm.path, warm.cam)),
+                    Err(e) => warn!("Error flushing camera, reopening it: {e:#}"),
+                }
+            }
+            self.stop_camera(warm.cam);
+        }
+        if let Some(cam_drop) = self.cam_drop.take() {
+            let _ = cam_drop
+                .join()
+                .map_err(|_| warn!("Error joining camera drop thread"));
+        }
+        camera::open(config)
+    }
+
+    /// Keep the camera open for the next request, or stop it if that is disabled.
+    fn put_camera(&mut self, config: &Config, path: PathBuf, cam: Box<dyn FrameSource>) {
+        if self.config.camera_idle_timeout().is_zero() {
+            self.stop_camera(cam);
+            return;
+        }
+        self.warm = Some(WarmCamera {
+            cameras: config.cameras().to_vec(),
+            path,
+            cam,
+            last_used: Instant::now(),
+        });
+    }
+
+    /// Stop the camera in the background, so the caller does not wait for it
+    fn stop_camera(&mut self, mut cam: Box<dyn FrameSource>) {
+        if let Some(cam_drop) = self.cam_drop.take() {
+            let _ = cam_drop
+                .join()
+                .map_err(|_| warn!("Error joining camera drop thread"));
+        }
+        self.cam_drop = Some(std::thread::spawn(move || {
+            let _ = cam.stop().map_err(|e| warn!("Error stopping camera: {e}"));
+        }));
+    }
+
+    fn close_camera(&mut self) {
+        if let Some(warm) = self.warm.take() {
+            info!("Closing camera {}", warm.path.display());
+            self.stop_camera(warm.cam);
+        }
+    }
+
+    /// Close the warm camera once it has been idle for `camera_idle_timeout`
+    fn close_idle_camera(&mut self) {
+        if self
+            .warm
+            .as_ref()
+            .is_some_and(|warm| warm.last_used.elapsed() >= self.config.camera_idle_timeout())
+        {
+            self.close_camera();
+        }
+    }
 }
 
 fn check_match(
     _ctx: &mut Context,
-    State {
-        fr,
-        config,
-        cam_drop,
-    }: &mut State,
-    (_username, timeout): (String, u64),
+    state: &mut State,
+    (username, timeout): (String, u64),
 ) -> YahalloResult<()> {
-    if !fr.has_faces() {
-        // In the future, we should check for this particular user
-        warn!("No faces in the database!");
-        return Err(Error::NoData);
+    let config = &state.config.for_user(&username);
+    if !state.fr.has_faces(&username) {
+        warn!("No faces enrolled for {username}");
+        return Err(Error::UnknownUser);
+    }
+    // a timeout set for the user takes precedence over the one passed by the caller,
+    // which takes precedence over the global one
+    let timeout = match timeout {
+        0 => config.timeout(),
+        _ if state.config.is_user_override(&username, "timeout") => config.timeout(),
+        secs => Duration::from_secs(secs),
+    };
+    // the timeout comes from the caller, so don't trust it to fit
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| Error::Other(anyhow!("Timeout of {timeout:?} is too long")))?;
+    let (camera, mut cam) = open_camera(state, config, deadline)?;
+    let res = find_match(
+        &state.fr,
+        config,
+        &username,
+        cam.as_mut(),
+        &camera,
+        deadline,
+    );
+    match res {
+        Ok(()) | Err(Error::Timeout | Error::LivenessFailed) => {
+            state.put_camera(config, camera, cam)
+        }
+        // the camera might be in a bad state, don't reuse it
+        Err(_) => state.stop_camera(cam),
     }
-    if let Some(cam_drop) = cam_drop.take() {
-        let _ = cam_drop
-            .join()
-            .map_err(|_| warn!("Error joining camera drop thread"));
+    res
+}
+
+/// First delay before retrying to open a busy camera, doubled after each try
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Open the camera, retrying until the deadline if it is busy or fails.
+///
+/// Only when all the cameras are missing is that reported right away, they are unlikely to
+/// show up in time. [`yahallo::camera::open`] reports any other error over that.
+fn open_camera(
+    state: &mut State,
+    config: &Config,
+    deadline: Instant,
+) -> YahalloResult<(PathBuf, Box<dyn FrameSource>)> {
+    let mut delay = RETRY_DELAY;
+    loop {
+        let err = match state.take_camera(config) {
+            Ok(opened) => return Ok(opened),
+            Err(e) => Error::from(e),
+        };
+        let now = Instant::now();
+        if matches!(err, Error::CameraMissing) || !err.is_camera_error() || now + delay >= deadline
+        {
+            return Err(err);
+        }
+        info!("{err} Retrying in {delay:?}");
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
     }
-    let mut cam = Cam::start(config.camera_path())?;
-    let start = Instant::now();
-    let timeout = Duration::from_secs(timeout.max(2));
+}
+
+/// Capture frames until enough of them match a known face of the user, or the deadline passes.
+///
+/// With `blink_liveness`, the user's face should also blink before the deadline, without
+/// any frame of another face, a spoof or no face in between.
+/// Faces that look like a screen or print as per `spoof_threshold` don't count.
+fn find_match(
+    fr: &FaceRecognizer,
+    config: &Config,
+    username: &str,
+    cam: &mut dyn FrameSource,
+    camera: &Path,
+    deadline: Instant,
+) -> YahalloResult<()> {
+    let mut consensus = Consensus::from_config(config);
+    let mut blink = config
+        .blink_liveness()
+        .then(|| BlinkDetector::new(config.blink_threshold()));
+    // whether the face matched, but didn't pass the liveness checks
+    let mut not_live = false;
     loop {
-        if start.elapsed() >= timeout {
+        if Instant::now() >= deadline {
+            if not_live {
+                warn!("Face matched, but failed the liveness checks!");
+                return Err(Error::LivenessFailed);
+            }
             warn!("Timeout trying to detect face!");
-            *cam_drop = Some(std::thread::spawn(move || {
-                let _ = cam.stop().map_err(|e| warn!("Error stopping camera: {e}"));
-            }));
             return Err(Error::Timeout);
         }
         let frame = cam.capture()?;
-        let img = process_image(frame)?;
+        let img = process_image(frame, config)?;
         if is_dark(&img, config.dark_threshold()) {
             info!("frame too dark!");
             continue;
         } else {
             info!("looking for matches");
         }
-        let img = to_rgb(&img);
-        let matrix = img_to_dlib(&img)?;
-        if let Some(model) = fr.check_match(&matrix, config)? {
-            println!("{}", model.label());
-            // TODO: Check username!!
-            break;
-        } else {
-            println!("No match");
+        let matrix = img_to_dlib(&to_rgb(&img))?;
+        let Some((face, res)) = fr.check_match(&matrix, config, username)? else {
+            info!("No match");
+            if let Some(blink) = &mut blink {
+                blink.reset();
+            }
+            continue;
+        };
+        let scale = f64::from(img.width()) / f64::from(matrix.size().0);
+        let spoof = SpoofFeatures::from_face(&img, &scale_rect(&face, scale));
+        let spoof_score = spoof.as_ref().map_or(0.0, SpoofFeatures::score);
+        if let Some(spoof) = spoof {
+            debug!("Spoof {spoof}");
+        }
+        let is_spoof = spoof_score < config.spoof_threshold();
+        if let Some(blink) = &mut blink {
+            // the blink has to come from the user's face, not someone next to it. That face
+            // may not match while the eyes are closed, so only start over on other faces.
+            if is_spoof || !FaceRecognizer::could_be_user(&res, config) {
+                blink.reset();
+            } else if let Some(ear) = fr.eye_aspect_ratio(&matrix, &face) {
+                debug!("Eye aspect ratio {ear:.3}");
+                blink.push(ear);
+            }
+        }
+        if !FaceRecognizer::is_match(&res, config) {
+            consensus.push(None);
+            info!("No match, closest was {res}, spoof score {spoof_score:.2}");
+            continue;
+        }
+        if is_spoof {
+            consensus.push(None);
+            info!("Matched {res}, but it looks like a spoof with score {spoof_score:.2}");
+            not_live = true;
+            continue;
         }
+        let Some(mean) = consensus.push(Some(res.distance())) else {
+            info!(
+                "Matched {res}, {} of {} frames needed",
+                consensus.matches(),
+                consensus.required()
+            );
+            continue;
+        };
+        if blink.as_ref().is_some_and(|b| !b.blinked()) {
+            info!("Matched {res}, waiting for a blink");
+            not_live = true;
+            continue;
+        }
+        info!(
+            "Matched {res} using camera {}, spoof score {spoof_score:.2}, \
+            mean distance {mean:.3} over {} of the last {} frames",
+            camera.display(),
+            consensus.matches(),
+            consensus.window()
+        );
+        return Ok(());
     }
-    *cam_drop = Some(std::thread::spawn(move || {
-        let _ = cam.stop().map_err(|e| warn!("Error stopping camera: {e}"));
-    }));
-    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     pretty_env_logger::formatted_timed_builder()
         .filter_level(log::LevelFilter::Trace)
         .init();
+    let args = Args::parse();
 
     let c = Connection::new_system()?;
     const NAME: &str = "com.iamkroot.yahallo";
@@ -116,9 +357,49 @@ fn main() -> anyhow::Result<()> {
                 Ok((res,))
             },
         );
-        // TODO: Add a reload faces method?
+        b.method(
+            "ReloadConfig",
+            (),
+            ("result",),
+            |_ctx, state: &mut State, (): ()| {
+                let res = match state.reload() {
+                    Ok(_) => DbusResult::Success,
+                    Err(e) => {
+                        warn!("Failed to reload config: {e:#}");
+                        DbusResult::Error(e.into())
+                    }
+                };
+                Ok((res,))
+            },
+        );
     });
-    cr.insert("/", &[iface_token], State::myconfig()?);
-    cr.serve(&c)?;
-    Ok(())
+    cr.insert("/", &[iface_token], State::myconfig(args)?);
+
+    // Same as `cr.serve`, but we also need to access the state on SIGHUP
+    let cr = Arc::new(Mutex::new(cr));
+    {
+        let cr = cr.clone();
+        c.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                cr.lock().unwrap().handle_message(msg, conn).unwrap();
+                true
+            }),
+        );
+    }
+    let sighup = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, sighup.clone())?;
+    // Also bounds how long the camera stays on past its idle timeout
+    loop {
+        c.process(Duration::from_secs(1))?;
+        let mut cr = cr.lock().unwrap();
+        let state: &mut State = cr.data_mut(&"/".into()).expect("state not registered");
+        if sighup.swap(false, Ordering::Relaxed) {
+            info!("Got SIGHUP");
+            if let Err(e) = state.reload() {
+                warn!("Failed to reload config: {e:#}");
+            }
+        }
+        state.close_idle_camera();
+    }
 }
@@ -4,7 +4,7 @@ use ctor::ctor;
 use dbus::blocking::Connection;
 use log::{error, info, warn};
 use pamsm::{pam_module, Pam, PamError, PamFlags, PamLibExt, PamServiceModule};
-use yahallo::DbusResult;
+use yahallo::{DbusResult, Error};
 
 struct YahalloDbus;
 
@@ -103,9 +103,16 @@ impl PamServiceModule for YahalloDbus {
                 eprintln!("wut {e2}");
                 return PamError::CONV_ERR;
             };
+            if err.is_camera_error() {
+                // we couldn't even try, so let the next module (like the password prompt) take over
+                return PamError::AUTHINFO_UNAVAIL;
+            }
+            if let Error::UnknownUser = err {
+                return PamError::USER_UNKNOWN;
+            }
             return PamError::AUTH_ERR;
         }
-        // TODO: Verify username
+        // the daemon only matches against the faces of `user`
         PamError::SUCCESS
     }
 }
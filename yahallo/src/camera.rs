@@ -1,83 +1,393 @@
 use std::num::NonZeroU32;
-use std::path::Path;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::{anyhow, bail, Context, Result};
 
+use crate::config::Config;
+use crate::Error;
+
+pub mod emitter;
+mod enumerate;
+mod file;
+pub mod format;
+mod mode;
+mod replay;
+pub mod transform;
+
+pub use enumerate::{formats, list_devices, DeviceInfo, FormatModes};
+pub use file::{ImageDir, RawDump};
+pub use replay::{record, Replay};
+pub use rscam::ResolutionInfo;
+
+/// Something that produces frames, like a camera.
+pub trait FrameSource: Send {
+    /// Get the next frame
+    fn capture(&mut self) -> Result<Frame>;
+
+    /// Expected time between consecutive frames
+    fn interval(&self) -> Duration;
+
+    fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)>;
+
+    fn stop(&mut self) -> Result<()>;
+
+    /// Drop the frames buffered while nobody was capturing, so the next one is fresh
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Which camera to use
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraSpec {
+    /// A device node, or a directory of frames.
+    ///
+    /// Can be one of the stable `/dev/v4l/by-id` or `/dev/v4l/by-path` symlinks.
+    Path(PathBuf),
+    /// Detect the IR camera automatically
+    Auto,
+    /// USB vendor and product ID, from `usb:VVVV:PPPP`
+    Usb { vendor: u16, product: u16 },
+    /// Card name reported by the driver, from `name:...`
+    Name(String),
+}
+
+impl CameraSpec {
+    /// Parse the spec from the config, resolving relative paths against `base_dir`
+    pub(crate) fn parse(s: &str, base_dir: &Path) -> Result<Self> {
+        if s == "auto" {
+            return Ok(Self::Auto);
+        }
+        if let Some(id) = s.strip_prefix("usb:") {
+            let (vendor, product) = id
+                .split_once(':')
+                .ok_or_else(|| anyhow!("expected usb:VVVV:PPPP, found {s}"))?;
+            let hex = |v| {
+                u16::from_str_radix(v, 16).with_context(|| format!("invalid USB ID {v} in {s}"))
+            };
+            return Ok(Self::Usb {
+                vendor: hex(vendor)?,
+                product: hex(product)?,
+            });
+        }
+        if let Some(name) = s.strip_prefix("name:") {
+            if name.trim().is_empty() {
+                bail!("empty camera name");
+            }
+            return Ok(Self::Name(name.trim().to_owned()));
+        }
+        Ok(Self::Path(base_dir.join(s)))
+    }
+
+    /// Find the path of the camera.
+    ///
+    /// This is done every time the camera is opened, since `/dev/videoN` numbers
+    /// can change after suspend or when devices are plugged in.
+    pub fn resolve(&self) -> Result<PathBuf> {
+        match self {
+            Self::Path(path) => {
+                if !path.exists() {
+                    return Err(missing(format!(
+                        "Camera {} not found. {}",
+                        path.display(),
+                        enumerate::available()
+                    )));
+                }
+                // follow by-id/by-path symlinks, so the logs show the actual device
+                let resolved = path
+                    .canonicalize()
+                    .with_context(|| format!("Camera {}", path.display()))?;
+                if &resolved != path {
+                    log::info!("Camera {} is {}", path.display(), resolved.display());
+                }
+                Ok(resolved)
+            }
+            Self::Auto => enumerate::auto_detect(),
+            Self::Usb { .. } | Self::Name(_) => enumerate::find(self),
+        }
+    }
+}
+
+impl std::fmt::Display for CameraSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "{}", path.display()),
+            Self::Auto => write!(f, "auto"),
+            Self::Usb { vendor, product } => write!(f, "usb:{vendor:04x}:{product:04x}"),
+            Self::Name(name) => write!(f, "name:{name}"),
+        }
+    }
+}
+
+/// Classify an IO error from the camera, so callers can tell a busy camera from a missing one
+pub(crate) fn io_error(e: std::io::Error) -> Error {
+    match e.raw_os_error() {
+        Some(libc::EBUSY) => Error::CameraBusy,
+        Some(libc::ENOENT | libc::ENODEV | libc::ENXIO) => Error::CameraMissing,
+        _ => Error::CameraIoError(e.to_string()),
+    }
+}
+
+/// [`Error::CameraMissing`], with `msg` saying what was looked for
+pub(crate) fn missing(msg: String) -> anyhow::Error {
+    anyhow::Error::new(Error::CameraMissing).context(msg)
+}
+
+/// Open the first camera from the config that works.
+///
+/// Returns the path of the camera that was opened, along with the frame source.
+/// If none works, the error is [`Error::CameraBusy`] if any camera was busy, and only
+/// [`Error::CameraMissing`] if all of them were missing.
+pub fn open(config: &Config) -> Result<(PathBuf, Box<dyn FrameSource>)> {
+    // a busy camera may be free soon, so that matters most, and missing ones the least
+    let rank = |e: &anyhow::Error| match e.downcast_ref::<Error>() {
+        Some(Error::CameraBusy) => 2,
+        Some(Error::CameraMissing) => 0,
+        _ => 1,
+    };
+    let mut worst_err: Option<anyhow::Error> = None;
+    for camera in config.cameras() {
+        let res = camera
+            .resolve()
+            .and_then(|path| Ok((open_path(&path, config)?, path)));
+        match res {
+            Ok((src, path)) => {
+                log::info!("Opened camera {}", path.display());
+                return Ok((path, src));
+            }
+            Err(e) => {
+                log::warn!("Couldn't open camera {camera}: {e:#}");
+                if worst_err.as_ref().is_none_or(|old| rank(&e) > rank(old)) {
+                    worst_err = Some(e);
+                }
+            }
+        }
+    }
+    let cameras = config
+        .cameras()
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>();
+    let err = worst_err.unwrap_or_else(|| anyhow!("No cameras configured"));
+    Err(err.context(format!("Couldn't open any of {}", cameras.join(", "))))
+}
+
+/// Whether `path` is a file of raw frames, to be played back as a [`RawDump`]
+pub(crate) fn is_raw_dump(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == file::RAW_DUMP_EXT)
+}
+
+/// Open the frame source at `path`.
+///
+/// A directory is read as a recorded session if it has a [`replay::SESSION_FILE`],
+/// else as a sequence of image files. A `.gray` file is read as a [`RawDump`] of frames
+/// of `camera_resolution`, at `camera_fps`. Anything else is opened as a V4L2 camera.
+pub fn open_path(path: &Path, config: &Config) -> Result<Box<dyn FrameSource>> {
+    if path.join(replay::SESSION_FILE).exists() {
+        Ok(Box::new(Replay::open(path)?))
+    } else if path.is_dir() {
+        Ok(Box::new(ImageDir::open(path)?))
+    } else if is_raw_dump(path) {
+        let resolution = config.camera_resolution().ok_or_else(|| {
+            anyhow!(
+                "`camera_resolution` is needed to play back {}",
+                path.display()
+            )
+        })?;
+        let interval = match config.camera_fps() {
+            Some(fps) => Duration::try_from_secs_f64(1.0 / fps)
+                .with_context(|| format!("invalid `camera_fps` {fps}"))?,
+            None => file::DEFAULT_INTERVAL,
+        };
+        Ok(Box::new(RawDump::open(path, resolution, interval)?))
+    } else {
+        Ok(Box::new(Cam::start(path, config)?))
+    }
+}
+
+/// A single frame from a [`FrameSource`]
+pub struct Frame {
+    pub resolution: (u32, u32),
+    /// FourCC of the pixel format
+    pub format: [u8; 4],
+    data: FrameData,
+}
+
+enum FrameData {
+    /// Buffer mapped from the camera, avoids a copy
+    V4l2(rscam::Frame),
+    Owned(Vec<u8>),
+}
+
+impl Frame {
+    pub fn new(resolution: (u32, u32), format: [u8; 4], data: Vec<u8>) -> Self {
+        Self {
+            resolution,
+            format,
+            data: FrameData::Owned(data),
+        }
+    }
+}
+
+impl From<rscam::Frame> for Frame {
+    fn from(frame: rscam::Frame) -> Self {
+        Self {
+            resolution: frame.resolution,
+            format: frame.format,
+            data: FrameData::V4l2(frame),
+        }
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            FrameData::V4l2(frame) => frame,
+            FrameData::Owned(data) => data,
+        }
+    }
+}
+
+/// `V4L2_CID_*` controls from `linux/v4l2-controls.h`
+const CID_BRIGHTNESS: u32 = 0x0098_0900;
+const CID_GAIN: u32 = 0x0098_0913;
+const CID_EXPOSURE_AUTO: u32 = 0x009a_0901;
+const CID_EXPOSURE_ABSOLUTE: u32 = 0x009a_0902;
+/// `V4L2_EXPOSURE_MANUAL`, for [`CID_EXPOSURE_AUTO`]
+const EXPOSURE_MANUAL: i32 = 1;
+
+/// A V4L2 camera
 pub struct Cam {
     cam: rscam::Camera,
     config: rscam::Config<'static>,
+    emitter: Option<emitter::Emitter>,
 }
 
 impl Cam {
-    pub fn start(camera_path: impl AsRef<Path>) -> Result<Self> {
+    pub fn start(camera_path: impl AsRef<Path>, config: &Config) -> Result<Self> {
         let device = camera_path
             .as_ref()
             .to_str()
             .ok_or_else(|| anyhow!("Invalid camera path {}", camera_path.as_ref().display()))?;
-        let mut cam = rscam::Camera::new(device).context("cam open err")?;
-        let rscam_config = Self::configure(&cam)?;
-        cam.start(&rscam_config)?;
-        Ok(Self {
+        let mut cam = rscam::Camera::new(device)
+            .map_err(io_error)
+            .with_context(|| format!("couldn't open {device}"))?;
+        let rscam_config = Self::configure(&cam, config)?;
+        // Not fatal, the controls may be meant for another camera in the list
+        Self::set_controls(&cam, config);
+        let emitter = match config.emitter() {
+            [] => None,
+            controls => emitter::Emitter::enable(camera_path.as_ref(), controls)
+                .map_err(|e| log::warn!("Couldn't enable the IR emitter: {e:#}"))
+                .ok(),
+        };
+        cam.start(&rscam_config).map_err(|e| match e {
+            rscam::Error::Io(e) => anyhow!(io_error(e)),
+            e => anyhow!(e),
+        })?;
+        let mut cam = Self {
             cam,
             config: rscam_config,
-        })
+            emitter,
+        };
+        cam.warm_up(config)?;
+        Ok(cam)
     }
 
-    pub fn interval(&self) -> Duration {
-        Duration::from_secs_f64(self.config.interval.0 as f64 / self.config.interval.1 as f64)
+    /// Set the exposure, gain and brightness from the config, warning about the ones the
+    /// camera doesn't support.
+    fn set_controls(cam: &rscam::Camera, config: &Config) {
+        let set = |id, value: i32, what: &str| {
+            cam.set_control(id, &value)
+                .map_err(|e| log::warn!("Couldn't set {what}: {e}"))
+                .is_ok()
+        };
+        if let Some(exposure) = config.camera_exposure() {
+            if set(CID_EXPOSURE_AUTO, EXPOSURE_MANUAL, "manual exposure") {
+                set(CID_EXPOSURE_ABSOLUTE, exposure, "`camera_exposure`");
+            }
+        }
+        if let Some(gain) = config.camera_gain() {
+            set(CID_GAIN, gain, "`camera_gain`");
+        }
+        if let Some(brightness) = config.camera_brightness() {
+            set(CID_BRIGHTNESS, brightness, "`camera_brightness`");
+        }
     }
 
-    pub fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)> {
-        let (w, h) = self.config.resolution;
-        Ok((w.try_into()?, h.try_into()?))
+    /// Discard frames until the auto exposure settles, or `camera_warmup_frames` have gone by.
+    ///
+    /// The exposure counts as settled once the mean brightness changes by at most
+    /// `camera_warmup_tolerance` between consecutive frames.
+    fn warm_up(&mut self, config: &Config) -> Result<()> {
+        let max_frames = config.camera_warmup_frames();
+        let tolerance = config.camera_warmup_tolerance();
+        let mut prev: Option<f64> = None;
+        for i in 1..=max_frames {
+            let img = crate::process_image(self.capture()?, config)?;
+            let brightness = crate::mean_brightness(&img);
+            if prev.is_some_and(|prev| (brightness - prev).abs() <= tolerance) {
+                log::debug!("Brightness settled at {brightness:.1} after {i} frames");
+                return Ok(());
+            }
+            prev = Some(brightness);
+        }
+        if max_frames > 0 {
+            log::debug!("Brightness did not settle in {max_frames} frames");
+        }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    fn dump_resolutions(cam: &rscam::Camera) {
-        for fmt in cam.formats() {
-            let Ok(fmti) = fmt else {
-                continue;
-            };
-            dbg!(&fmti);
-            let _ = dbg!(cam.resolutions(&fmti.format));
-        }
-    }
-
-    fn configure(cam: &rscam::Camera) -> Result<rscam::Config<'static>> {
-        let format = b"GREY";
-        let res = {
-            let mut res = None;
-            for fmt in cam.formats() {
-                let Ok(fmti) = fmt else {
-                    continue;
-                };
-                if &fmti.format == format {
-                    res =
-                        Some(cam.resolutions(format).with_context(|| {
-                            format!("format {}", String::from_utf8_lossy(format))
-                        })?);
-                }
-            }
-            res.ok_or_else(|| anyhow!("No suitable resolution found"))?
+    /// Pick the first format from the config's preference list that the camera offers
+    fn negotiate_format(cam: &rscam::Camera, config: &Config) -> Result<&'static [u8; 4]> {
+        let offered = cam.formats().filter_map(|fmt| fmt.ok()).collect::<Vec<_>>();
+        let format = config
+            .camera_formats()
+            .iter()
+            .find(|pref| offered.iter().any(|fmti| &fmti.format == *pref))
+            .and_then(|pref| format::SUPPORTED_FORMATS.iter().find(|f| f == &pref));
+        let Some(format) = format else {
+            let offered = offered
+                .iter()
+                .map(|fmti| {
+                    format!(
+                        "{} ({})",
+                        format::fourcc_str(&fmti.format),
+                        fmti.description
+                    )
+                })
+                .collect::<Vec<_>>();
+            bail!(
+                "Camera does not support any of the formats {}. It offers: {}",
+                format::format_list(config.camera_formats()),
+                offered.join(", ")
+            );
         };
-        let resolution = match res {
-            rscam::ResolutionInfo::Discretes(v) => v
-                .first()
-                .ok_or_else(|| anyhow::anyhow!("No resolutions! {v:#?}"))
-                .cloned()?,
-            _ => anyhow::bail!("Only support discrete resolutions"),
-        };
-        let interval = match cam
+        log::info!("Using format {}", format::fourcc_str(format));
+        Ok(format)
+    }
+
+    fn configure(cam: &rscam::Camera, config: &Config) -> Result<rscam::Config<'static>> {
+        let format = Self::negotiate_format(cam, config)?;
+        let res = cam
+            .resolutions(format)
+            .with_context(|| format!("format {}", format::fourcc_str(format)))?;
+        let resolution = mode::pick_resolution(&res, config.camera_resolution())?;
+        let intervals = cam
             .intervals(format, resolution)
-            .context("camera interval")?
-        {
-            rscam::IntervalInfo::Discretes(v) => v
-                .first()
-                .ok_or_else(|| anyhow!("no intervals! {v:?}"))
-                .cloned()?,
-            _ => bail!("Only support discrete inervals"),
-        };
+            .context("camera interval")?;
+        let interval = mode::pick_interval(&intervals, config.camera_fps())?;
+        log::info!(
+            "Using {}x{} at {:.1} fps",
+            resolution.0,
+            resolution.1,
+            interval.1 as f64 / interval.0 as f64
+        );
         Ok(rscam::Config {
             interval,
             resolution,
@@ -85,13 +395,36 @@ impl Cam {
             ..Default::default()
         })
     }
+}
+
+impl FrameSource for Cam {
+    fn capture(&mut self) -> Result<Frame> {
+        Ok(self.cam.capture().map_err(io_error)?.into())
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs_f64(self.config.interval.0 as f64 / self.config.interval.1 as f64)
+    }
+
+    fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)> {
+        let (w, h) = self.config.resolution;
+        Ok((w.try_into()?, h.try_into()?))
+    }
 
-    pub fn capture(&mut self) -> Result<rscam::Frame> {
-        Ok(self.cam.capture()?)
+    fn stop(&mut self) -> Result<()> {
+        self.cam.stop().context("cam stop err")?;
+        if let Some(mut emitter) = self.emitter.take() {
+            emitter.restore()?;
+        }
+        Ok(())
     }
 
-    pub fn stop(&mut self) -> Result<()> {
-        self.cam.stop().context("cam stop err")
+    fn flush(&mut self) -> Result<()> {
+        // the driver stops filling buffers once they are all full, so these hold the oldest frames
+        for _ in 0..self.config.nbuffers {
+            self.cam.capture().map_err(io_error)?;
+        }
+        Ok(())
     }
 }
 
@@ -105,7 +438,7 @@ mod tests {
     fn stream() {
         let _sw = Stopwatch::new("capture");
 
-        let mut cam = Cam::start("/dev/video2").expect("failed to start cam");
+        let mut cam = Cam::start("/dev/video2", &Config::default()).expect("failed to start cam");
 
         for i in 0..10 {
             println!("iter {i}");
@@ -113,4 +446,74 @@ mod tests {
             std::thread::sleep(Duration::from_millis(100));
         }
     }
+
+    #[test]
+    fn open_raw_dump() {
+        let dir = crate::utils::temp_dir("open-raw-dump");
+        let path = dir.join("frames.gray");
+        std::fs::write(&path, [1, 1, 2, 2]).unwrap();
+        assert!(open_path(&path, &Config::default()).is_err());
+
+        let config = Config::parse(
+            "camera_resolution = [2, 1]\ncamera_fps = 10",
+            Path::new("/"),
+        )
+        .unwrap();
+        let mut src = open_path(&path, &config).unwrap();
+        // a frame every 1e300 seconds doesn't fit in a duration
+        let slow = Config::parse(
+            "camera_resolution = [2, 1]\ncamera_fps = 1e-300",
+            Path::new("/"),
+        );
+        assert!(open_path(&path, &slow.unwrap()).is_err());
+        assert_eq!(src.interval(), Duration::from_millis(100));
+        assert_eq!(&*src.capture().unwrap(), &[1, 1]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn open_reports_worst_error() {
+        let dir = crate::utils::temp_dir("open-worst-error");
+        std::fs::write(dir.join("frames.gray"), [1, 1]).unwrap();
+        let error = |cameras: &str| {
+            let config = Config::parse(&format!("camera = [{cameras}]"), &dir).unwrap();
+            Error::from(open(&config).err().unwrap())
+        };
+        assert!(matches!(
+            error(r#""missing1", "missing2""#),
+            Error::CameraMissing
+        ));
+        // the raw dump fails without a resolution, which is not worth skipping
+        assert!(!matches!(
+            error(r#""missing1", "frames.gray", "missing2""#),
+            Error::CameraMissing
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_spec() {
+        let base = Path::new("/etc/yahallo");
+        assert_eq!(CameraSpec::parse("auto", base).unwrap(), CameraSpec::Auto);
+        assert_eq!(
+            CameraSpec::parse("/dev/v4l/by-id/usb-cam-video-index0", base).unwrap(),
+            CameraSpec::Path("/dev/v4l/by-id/usb-cam-video-index0".into())
+        );
+        let usb = CameraSpec::parse("usb:04F2:b6dd", base).unwrap();
+        assert_eq!(
+            usb,
+            CameraSpec::Usb {
+                vendor: 0x04f2,
+                product: 0xb6dd
+            }
+        );
+        assert_eq!(usb.to_string(), "usb:04f2:b6dd");
+        assert_eq!(
+            CameraSpec::parse("name: Integrated IR Camera", base).unwrap(),
+            CameraSpec::Name("Integrated IR Camera".into())
+        );
+        assert!(CameraSpec::parse("usb:04f2", base).is_err());
+        assert!(CameraSpec::parse("usb:04f2:xyz", base).is_err());
+        assert!(CameraSpec::parse("name:", base).is_err());
+    }
 }
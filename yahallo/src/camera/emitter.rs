@@ -0,0 +1,234 @@
+//! Turning on the IR emitter of cameras that need a vendor specific UVC control.
+//!
+//! Such cameras stream black frames until a value is written to a control in one of
+//! their extension units (XU). The controls are described in the `emitter` config key,
+//! and `yahallo probe-emitter` can find them.
+
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use toml::Value;
+
+const UVC_SET_CUR: u8 = 0x01;
+const UVC_GET_CUR: u8 = 0x81;
+const UVC_GET_MAX: u8 = 0x83;
+const UVC_GET_LEN: u8 = 0x85;
+
+/// `struct uvc_xu_control_query` from `linux/uvcvideo.h`
+#[repr(C)]
+struct UvcXuControlQuery {
+    unit: u8,
+    selector: u8,
+    query: u8,
+    size: u16,
+    data: *mut u8,
+}
+
+/// `_IOWR('u', 0x21, struct uvc_xu_control_query)`
+const UVCIOC_CTRL_QUERY: u32 = (3 << 30)
+    | ((std::mem::size_of::<UvcXuControlQuery>() as u32) << 16)
+    | ((b'u' as u32) << 8)
+    | 0x21;
+
+/// A value to write to an extension unit control
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmitterControl {
+    pub unit: u8,
+    pub selector: u8,
+    pub data: Vec<u8>,
+}
+
+impl EmitterControl {
+    /// The control as a table in the `emitter` config key
+    pub(crate) fn to_toml(&self) -> Value {
+        let mut table = toml::Table::new();
+        table.insert("unit".into(), Value::Integer(self.unit.into()));
+        table.insert("selector".into(), Value::Integer(self.selector.into()));
+        table.insert(
+            "data".into(),
+            Value::Array(
+                self.data
+                    .iter()
+                    .map(|&b| Value::Integer(b.into()))
+                    .collect(),
+            ),
+        );
+        Value::Table(table)
+    }
+}
+
+impl std::fmt::Display for EmitterControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unit {} selector {}", self.unit, self.selector)
+    }
+}
+
+/// Config file contents that set the `emitter` key to `controls`
+pub fn config_snippet(controls: &[EmitterControl]) -> String {
+    let mut table = toml::Table::new();
+    table.insert(
+        "emitter".into(),
+        Value::Array(controls.iter().map(|c| c.to_toml()).collect()),
+    );
+    toml::to_string(&table).expect("emitter controls are valid TOML")
+}
+
+/// A UVC device opened for querying extension unit controls.
+///
+/// This is separate from the capture stream, UVC allows both at once.
+pub struct UvcDevice {
+    file: File,
+}
+
+impl UvcDevice {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("couldn't open {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    fn query(&self, unit: u8, selector: u8, query: u8, data: &mut [u8]) -> std::io::Result<()> {
+        let mut q = UvcXuControlQuery {
+            unit,
+            selector,
+            query,
+            size: data.len() as u16,
+            data: data.as_mut_ptr(),
+        };
+        // SAFETY: `q` matches the kernel struct, and `data` outlives the call
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), UVCIOC_CTRL_QUERY as _, &mut q) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Size of the control's value in bytes
+    pub fn len(&self, unit: u8, selector: u8) -> std::io::Result<u16> {
+        let mut len = [0; 2];
+        self.query(unit, selector, UVC_GET_LEN, &mut len)?;
+        Ok(u16::from_le_bytes(len))
+    }
+
+    fn get(&self, unit: u8, selector: u8, query: u8) -> Result<EmitterControl> {
+        let mut data = vec![0; self.len(unit, selector)?.into()];
+        self.query(unit, selector, query, &mut data)?;
+        Ok(EmitterControl {
+            unit,
+            selector,
+            data,
+        })
+    }
+
+    /// Current value of the control
+    pub fn get_cur(&self, unit: u8, selector: u8) -> Result<EmitterControl> {
+        self.get(unit, selector, UVC_GET_CUR)
+            .with_context(|| format!("couldn't read unit {unit} selector {selector}"))
+    }
+
+    /// Maximum value of the control
+    pub fn get_max(&self, unit: u8, selector: u8) -> Result<EmitterControl> {
+        self.get(unit, selector, UVC_GET_MAX)
+            .with_context(|| format!("couldn't read max of unit {unit} selector {selector}"))
+    }
+
+    pub fn set_cur(&self, control: &EmitterControl) -> Result<()> {
+        let len = self.len(control.unit, control.selector)?;
+        if usize::from(len) != control.data.len() {
+            bail!("{control} takes {len} bytes, got {}", control.data.len());
+        }
+        let mut data = control.data.clone();
+        self.query(control.unit, control.selector, UVC_SET_CUR, &mut data)
+            .with_context(|| format!("couldn't set {control}"))
+    }
+
+    /// Find all the extension unit controls offered by the device, as (unit, selector).
+    ///
+    /// The driver rejects unknown units and selectors from the USB descriptors, so probing
+    /// every ID is quick. A missing unit and a missing selector both give `ENOENT`, so a
+    /// unit can only be ruled out by trying all of its selectors.
+    pub fn controls(&self) -> Vec<(u8, u8)> {
+        let mut controls = vec![];
+        for unit in 1..=u8::MAX {
+            for selector in 1..=u8::MAX {
+                if self.len(unit, selector).is_ok() {
+                    controls.push((unit, selector));
+                }
+            }
+        }
+        debug!("Found extension unit controls {controls:?}");
+        controls
+    }
+}
+
+/// Emitter controls applied to a device, restored to their old values when dropped.
+pub(crate) struct Emitter {
+    device: UvcDevice,
+    /// The values the controls had before we changed them
+    saved: Vec<EmitterControl>,
+}
+
+impl Emitter {
+    pub(crate) fn enable(path: &Path, controls: &[EmitterControl]) -> Result<Self> {
+        let mut emitter = Self {
+            device: UvcDevice::open(path)?,
+            saved: vec![],
+        };
+        // on errors, dropping the emitter restores the controls set so far
+        for control in controls {
+            let old = emitter.device.get_cur(control.unit, control.selector)?;
+            emitter.device.set_cur(control)?;
+            emitter.saved.push(old);
+        }
+        info!("Enabled IR emitter on {}", path.display());
+        Ok(emitter)
+    }
+
+    /// Put the controls back to their old values
+    pub(crate) fn restore(&mut self) -> Result<()> {
+        while let Some(control) = self.saved.pop() {
+            self.device.set_cur(&control)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        if let Err(e) = self.restore() {
+            warn!("Couldn't restore emitter controls: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_number() {
+        // as computed by the kernel headers on 64-bit
+        if std::mem::size_of::<usize>() == 8 {
+            assert_eq!(UVCIOC_CTRL_QUERY, 0xc0107521);
+        }
+    }
+
+    #[test]
+    fn snippet() {
+        let controls = [EmitterControl {
+            unit: 14,
+            selector: 6,
+            data: vec![1, 3, 2],
+        }];
+        assert_eq!(
+            config_snippet(&controls),
+            "[[emitter]]\ndata = [1, 3, 2]\nselector = 6\nunit = 14\n"
+        );
+    }
+}
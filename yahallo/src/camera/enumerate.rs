@@ -0,0 +1,295 @@
+//! Listing the V4L2 devices on the system, and what they support.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+use super::{format, missing, CameraSpec};
+
+const SYSFS_V4L: &str = "/sys/class/video4linux";
+/// Directories with udev's stable symlinks to the device nodes
+const STABLE_LINK_DIRS: &[&str] = &["/dev/v4l/by-id", "/dev/v4l/by-path"];
+
+/// Frame sizes supported by a camera for one format
+#[derive(Debug)]
+pub struct FormatModes {
+    pub format: [u8; 4],
+    pub description: String,
+    pub resolutions: rscam::ResolutionInfo,
+}
+
+/// A V4L2 device that can capture frames
+#[derive(Debug)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    /// Card name reported by the driver
+    pub name: Option<String>,
+    /// USB vendor and product ID
+    pub usb_id: Option<(u16, u16)>,
+    /// Stable symlinks pointing to the device
+    pub links: Vec<PathBuf>,
+    pub formats: Vec<FormatModes>,
+    /// Whether this looks like an infrared camera
+    pub likely_ir: bool,
+}
+
+impl DeviceInfo {
+    /// Whether we can read frames in any of the formats offered by the device
+    pub fn is_supported(&self) -> bool {
+        self.formats
+            .iter()
+            .any(|f| format::SUPPORTED_FORMATS.contains(&f.format))
+    }
+
+    /// Whether the device is the one described by a `usb:` or `name:` spec
+    fn matches(&self, spec: &CameraSpec) -> bool {
+        match spec {
+            CameraSpec::Usb { vendor, product } => self.usb_id == Some((*vendor, *product)),
+            CameraSpec::Name(name) => self
+                .name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name)),
+            CameraSpec::Path(_) | CameraSpec::Auto => false,
+        }
+    }
+
+    /// The ways this device can be referred to in the config
+    pub fn specs(&self) -> Vec<String> {
+        let mut specs = vec![self.path.display().to_string()];
+        specs.extend(self.links.iter().map(|l| l.display().to_string()));
+        if let Some((vendor, product)) = self.usb_id {
+            specs.push(CameraSpec::Usb { vendor, product }.to_string());
+        }
+        if let Some(name) = &self.name {
+            specs.push(CameraSpec::Name(name.clone()).to_string());
+        }
+        specs
+    }
+}
+
+/// List the formats and resolutions offered by the camera
+pub fn formats(cam: &rscam::Camera) -> Vec<FormatModes> {
+    cam.formats()
+        .filter_map(|fmt| fmt.ok())
+        .filter_map(|fmti| {
+            let resolutions = cam
+                .resolutions(&fmti.format)
+                .map_err(|e| debug!("resolutions for {}: {e}", format::fourcc_str(&fmti.format)))
+                .ok()?;
+            Some(FormatModes {
+                format: fmti.format,
+                description: fmti.description,
+                resolutions,
+            })
+        })
+        .collect()
+}
+
+/// List all the `/dev/videoN` devices that can capture frames, in order of N.
+///
+/// Devices that can't be opened (busy, permissions, etc.) are skipped with a warning.
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    let mut nums = std::fs::read_dir("/dev")
+        .context("couldn't read /dev")?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            e.file_name()
+                .to_str()?
+                .strip_prefix("video")?
+                .parse::<u32>()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    nums.sort();
+    let mut devices = vec![];
+    for num in nums {
+        let path = PathBuf::from(format!("/dev/video{num}"));
+        let cam = match rscam::Camera::new(&path.to_string_lossy()) {
+            Ok(cam) => cam,
+            Err(e) => {
+                warn!("Skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+        let formats = formats(&cam);
+        if formats.is_empty() {
+            // metadata nodes, etc.
+            debug!("Skipping {}: no capture formats", path.display());
+            continue;
+        }
+        let name = card_name(&path);
+        let fourccs = formats.iter().map(|f| f.format).collect::<Vec<_>>();
+        devices.push(DeviceInfo {
+            likely_ir: is_likely_ir(name.as_deref(), &fourccs),
+            usb_id: usb_id(&path),
+            links: stable_links(&path),
+            path,
+            name,
+            formats,
+        });
+    }
+    Ok(devices)
+}
+
+/// Name of the card, as exposed in sysfs
+fn card_name(device: &Path) -> Option<String> {
+    let node = device.file_name()?;
+    let name = std::fs::read_to_string(Path::new(SYSFS_V4L).join(node).join("name")).ok()?;
+    Some(name.trim().to_owned())
+}
+
+/// USB vendor and product ID, as exposed in sysfs
+fn usb_id(device: &Path) -> Option<(u16, u16)> {
+    let node = device.file_name()?;
+    // `device` is the USB interface, the IDs are on its parent USB device
+    let interface = std::fs::canonicalize(Path::new(SYSFS_V4L).join(node).join("device")).ok()?;
+    let usb_device = interface.parent()?;
+    let read_id = |file| {
+        let id = std::fs::read_to_string(usb_device.join(file)).ok()?;
+        u16::from_str_radix(id.trim(), 16).ok()
+    };
+    Some((read_id("idVendor")?, read_id("idProduct")?))
+}
+
+/// The udev symlinks that point to `device`
+fn stable_links(device: &Path) -> Vec<PathBuf> {
+    let mut links = STABLE_LINK_DIRS
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|link| std::fs::canonicalize(link).is_ok_and(|target| target == device))
+        .collect::<Vec<_>>();
+    links.sort();
+    links
+}
+
+/// Guess whether a camera is infrared.
+///
+/// IR cameras are usually named so, or only offer grayscale frames.
+fn is_likely_ir(name: Option<&str>, formats: &[[u8; 4]]) -> bool {
+    let named_ir = name.is_some_and(|name| {
+        name.to_lowercase().contains("infrared")
+            || name
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| word == "IR")
+    });
+    let only_gray = !formats.is_empty() && formats.iter().all(|f| f == &format::GREY);
+    named_ir || only_gray
+}
+
+/// Pick the camera to use for `camera = "auto"`.
+///
+/// Prefers the first likely IR camera, falling back to the first usable one.
+pub fn auto_detect() -> Result<PathBuf> {
+    let devices = list_devices()?;
+    let usable = devices.iter().filter(|d| d.is_supported());
+    if let Some(dev) = usable.clone().find(|d| d.likely_ir) {
+        info!("Auto-detected IR camera {}", dev.path.display());
+        return Ok(dev.path.clone());
+    }
+    let dev = usable
+        .clone()
+        .next()
+        .ok_or_else(|| missing("No usable camera found".to_owned()))?;
+    warn!(
+        "No IR camera found, falling back to {}. This is easier to fool with a photo!",
+        dev.path.display()
+    );
+    Ok(dev.path.clone())
+}
+
+/// Find the device for a `usb:` or `name:` spec.
+///
+/// The IR and color sensors of a camera module often share a USB ID and name,
+/// so a likely IR match is preferred.
+pub(crate) fn find(spec: &CameraSpec) -> Result<PathBuf> {
+    let devices = list_devices()?;
+    let dev = find_in(&devices, spec)
+        .ok_or_else(|| missing(format!("No camera matches {spec}. {}", describe(&devices))))?;
+    info!("Camera {spec} is {}", dev.path.display());
+    Ok(dev.path.clone())
+}
+
+fn find_in<'a>(devices: &'a [DeviceInfo], spec: &CameraSpec) -> Option<&'a DeviceInfo> {
+    let matching = devices.iter().filter(|d| d.matches(spec));
+    matching
+        .clone()
+        .find(|d| d.likely_ir)
+        .or_else(|| matching.clone().next())
+}
+
+/// Describe the cameras on the system, for error messages
+pub(crate) fn available() -> String {
+    match list_devices() {
+        Ok(devices) => describe(&devices),
+        Err(e) => format!("Couldn't list cameras: {e:#}"),
+    }
+}
+
+fn describe(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "No cameras found".to_owned();
+    }
+    let mut s = "Available cameras:".to_owned();
+    for dev in devices {
+        s += &format!("\n  {}", dev.specs().join(", "));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ir_heuristic() {
+        assert!(is_likely_ir(Some("Integrated IR Camera"), &[format::YUYV]));
+        assert!(is_likely_ir(Some("Infrared Camera: Infrared"), &[]));
+        assert!(is_likely_ir(Some("Integrated Camera"), &[format::GREY]));
+        assert!(!is_likely_ir(
+            Some("Integrated Camera"),
+            &[format::GREY, format::MJPG]
+        ));
+        assert!(!is_likely_ir(Some("FIRE Webcam"), &[format::YUYV]));
+        assert!(!is_likely_ir(None, &[]));
+    }
+
+    #[test]
+    fn find_by_spec() {
+        let dev = |n: u32, name: &str, likely_ir| DeviceInfo {
+            path: PathBuf::from(format!("/dev/video{n}")),
+            name: Some(name.to_owned()),
+            usb_id: Some((0x04f2, 0xb6dd)),
+            links: vec![],
+            formats: vec![],
+            likely_ir,
+        };
+        let devices = [
+            dev(0, "Integrated Camera", false),
+            dev(2, "Integrated IR Camera", true),
+        ];
+        let found = |spec| find_in(&devices, &spec).map(|d| d.path.clone());
+        assert_eq!(
+            found(CameraSpec::Usb {
+                vendor: 0x04f2,
+                product: 0xb6dd
+            }),
+            Some("/dev/video2".into())
+        );
+        assert_eq!(
+            found(CameraSpec::Name("integrated camera".into())),
+            Some("/dev/video0".into())
+        );
+        assert_eq!(
+            found(CameraSpec::Usb {
+                vendor: 0x046d,
+                product: 0x0825
+            }),
+            None
+        );
+        assert_eq!(found(CameraSpec::Name("Webcam".into())), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Frame sources backed by files, for testing without a camera.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::format::GREY;
+use super::{Frame, FrameSource};
+
+/// Interval reported by sources that don't have one of their own
+pub(super) const DEFAULT_INTERVAL: Duration = Duration::from_millis(33);
+/// Extension of the files opened as a [`RawDump`]
+pub(super) const RAW_DUMP_EXT: &str = "gray";
+
+/// Plays back the images in a directory, in lexical order of their file names.
+///
+/// Images are converted to grayscale. Loops back to the first image after the last one.
+pub struct ImageDir {
+    files: Vec<PathBuf>,
+    next: usize,
+    resolution: (u32, u32),
+}
+
+impl ImageDir {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut files = std::fs::read_dir(dir)
+            .with_context(|| format!("couldn't read {}", dir.display()))?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|f| image::ImageFormat::from_path(f).is_ok());
+        files.sort();
+        let first = files
+            .first()
+            .ok_or_else(|| anyhow!("No images in {}", dir.display()))?;
+        let resolution = image::image_dimensions(first)
+            .with_context(|| format!("couldn't read {}", first.display()))?;
+        Ok(Self {
+            files,
+            next: 0,
+            resolution,
+        })
+    }
+}
+
+impl FrameSource for ImageDir {
+    fn capture(&mut self) -> Result<Frame> {
+        let path = &self.files[self.next];
+        self.next = (self.next + 1) % self.files.len();
+        let img = image::open(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?
+            .into_luma8();
+        if img.dimensions() != self.resolution {
+            bail!(
+                "{} is {:?}, expected {:?}",
+                path.display(),
+                img.dimensions(),
+                self.resolution
+            );
+        }
+        Ok(Frame::new(self.resolution, GREY, img.into_raw()))
+    }
+
+    fn interval(&self) -> Duration {
+        DEFAULT_INTERVAL
+    }
+
+    fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)> {
+        let (w, h) = self.resolution;
+        Ok((w.try_into()?, h.try_into()?))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Plays back a file of raw `GREY` frames, one after the other,
+/// like the output of `ffmpeg -f rawvideo -pix_fmt gray`.
+///
+/// Loops back to the first frame at the end of the file.
+pub struct RawDump {
+    file: File,
+    resolution: (u32, u32),
+    interval: Duration,
+}
+
+impl RawDump {
+    pub fn open(
+        path: impl AsRef<Path>,
+        resolution: (u32, u32),
+        interval: Duration,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+        let frame_len = resolution.0 as u64 * resolution.1 as u64;
+        let len = file.metadata()?.len();
+        if frame_len == 0 || len == 0 || len % frame_len != 0 {
+            bail!(
+                "{} is {len} bytes, not a multiple of the frame size {resolution:?}",
+                path.display()
+            );
+        }
+        Ok(Self {
+            file,
+            resolution,
+            interval,
+        })
+    }
+}
+
+impl FrameSource for RawDump {
+    fn capture(&mut self) -> Result<Frame> {
+        let mut data = vec![0; self.resolution.0 as usize * self.resolution.1 as usize];
+        match self.file.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.file.rewind()?;
+                self.file.read_exact(&mut data)?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Frame::new(self.resolution, GREY, data))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)> {
+        let (w, h) = self.resolution;
+        Ok((w.try_into()?, h.try_into()?))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::temp_dir;
+
+    #[test]
+    fn image_dir() {
+        let dir = temp_dir("image-dir");
+        for (name, val) in [("b.png", 20), ("a.png", 10)] {
+            image::GrayImage::from_pixel(4, 2, image::Luma([val]))
+                .save(dir.join(name))
+                .unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let mut src = ImageDir::open(&dir).unwrap();
+        let frames = (0..3).map(|_| src.capture().unwrap()).collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(frames[0].resolution, (4, 2));
+        assert_eq!(&*frames[0], &[10; 8]);
+        assert_eq!(&*frames[1], &[20; 8]);
+        // loops back to the start
+        assert_eq!(&*frames[2], &[10; 8]);
+    }
+
+    #[test]
+    fn raw_dump() {
+        let dir = temp_dir("raw-dump");
+        let path = dir.join("frames.gray");
+        std::fs::write(&path, [1, 1, 2, 2]).unwrap();
+        assert!(RawDump::open(&path, (3, 1), DEFAULT_INTERVAL).is_err());
+
+        let mut src = RawDump::open(&path, (2, 1), DEFAULT_INTERVAL).unwrap();
+        let frames = (0..3).map(|_| src.capture().unwrap()).collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&*frames[0], &[1, 1]);
+        assert_eq!(&*frames[1], &[2, 2]);
+        assert_eq!(&*frames[2], &[1, 1]);
+    }
+}
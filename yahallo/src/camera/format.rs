@@ -0,0 +1,103 @@
+//! Pixel formats we can read from the camera, and their conversion to grayscale.
+
+use std::borrow::Cow;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::Frame;
+
+/// 8-bit grayscale
+pub const GREY: [u8; 4] = *b"GREY";
+/// Packed YUV 4:2:2
+pub const YUYV: [u8; 4] = *b"YUYV";
+/// A JPEG image per frame
+pub const MJPG: [u8; 4] = *b"MJPG";
+
+/// All the formats that can be converted by [`to_gray`]
+pub const SUPPORTED_FORMATS: &[[u8; 4]] = &[GREY, YUYV, MJPG];
+
+/// Printable name of a FourCC
+pub fn fourcc_str(format: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(format)
+}
+
+/// Parse a FourCC, making sure that it is supported
+pub(crate) fn parse_fourcc(name: &str) -> Result<[u8; 4]> {
+    SUPPORTED_FORMATS
+        .iter()
+        .find(|f| f.as_slice() == name.as_bytes())
+        .copied()
+        .ok_or_else(|| {
+            anyhow!(
+                "unsupported format {name}, expected one of {}",
+                format_list(SUPPORTED_FORMATS)
+            )
+        })
+}
+
+/// Comma separated names of the formats
+pub(crate) fn format_list<'a>(formats: impl IntoIterator<Item = &'a [u8; 4]>) -> String {
+    formats
+        .into_iter()
+        .map(|f| fourcc_str(f))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Convert the frame to `GREY`, if it isn't already.
+pub(crate) fn to_gray(frame: Frame) -> Result<Frame> {
+    let (w, h) = frame.resolution;
+    let data = match frame.format {
+        GREY => return Ok(frame),
+        // Y0 U Y1 V - the luma is every other byte
+        YUYV => frame.iter().step_by(2).copied().collect(),
+        MJPG => {
+            let img = image::load_from_memory_with_format(&frame, image::ImageFormat::Jpeg)
+                .context("invalid MJPG frame")?
+                .into_luma8();
+            if img.dimensions() != frame.resolution {
+                bail!(
+                    "MJPG frame is {:?}, expected {:?}",
+                    img.dimensions(),
+                    frame.resolution
+                );
+            }
+            img.into_raw()
+        }
+        other => bail!("Can't convert {} frames", fourcc_str(&other)),
+    };
+    Ok(Frame::new((w, h), GREY, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yuyv() {
+        let frame = Frame::new((2, 1), YUYV, vec![10, 128, 20, 128]);
+        let gray = to_gray(frame).unwrap();
+        assert_eq!(gray.format, GREY);
+        assert_eq!(&*gray, &[10, 20]);
+    }
+
+    #[test]
+    fn mjpg() {
+        let img = image::GrayImage::from_pixel(16, 8, image::Luma([100]));
+        let mut jpeg = vec![];
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .encode_image(&img)
+            .unwrap();
+        let gray = to_gray(Frame::new((16, 8), MJPG, jpeg.clone())).unwrap();
+        assert_eq!(gray.resolution, (16, 8));
+        assert!(gray.iter().all(|&p| p.abs_diff(100) <= 2));
+
+        assert!(to_gray(Frame::new((8, 8), MJPG, jpeg)).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_fourcc("YUYV").unwrap(), YUYV);
+        assert!(parse_fourcc("RGB3").is_err());
+    }
+}
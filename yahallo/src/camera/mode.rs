@@ -0,0 +1,191 @@
+//! Choosing the resolution and frame interval to request from the camera.
+
+use anyhow::{anyhow, Result};
+use rscam::{IntervalInfo, ResolutionInfo};
+
+/// Pick the resolution closest to `preferred`.
+///
+/// Without a preference, the first discrete resolution or the smallest stepwise one is picked.
+pub(crate) fn pick_resolution(
+    info: &ResolutionInfo,
+    preferred: Option<(u32, u32)>,
+) -> Result<(u32, u32)> {
+    match info {
+        ResolutionInfo::Discretes(v) => {
+            let res = match preferred {
+                Some((w, h)) => v
+                    .iter()
+                    .min_by_key(|(rw, rh)| rw.abs_diff(w) + rh.abs_diff(h)),
+                None => v.first(),
+            };
+            res.copied()
+                .ok_or_else(|| anyhow!("No resolutions! {v:#?}"))
+        }
+        ResolutionInfo::Stepwise { min, max, step } => {
+            let Some((w, h)) = preferred else {
+                return Ok(*min);
+            };
+            Ok((snap(w, min.0, max.0, step.0), snap(h, min.1, max.1, step.1)))
+        }
+    }
+}
+
+/// Snap `val` to the closest of `min + k * step` that lies in `min..=max`.
+fn snap(val: u32, min: u32, max: u32, step: u32) -> u32 {
+    // A continuous range may be reported with no step
+    let step = step.max(1);
+    let val = val.clamp(min, max);
+    let k = ((val - min) as f64 / step as f64).round() as u32;
+    let snapped = min + k * step;
+    if snapped > max {
+        snapped - step
+    } else {
+        snapped
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Reduce the fraction, approximating it if it still doesn't fit in `u32`
+fn reduce(num: u64, den: u64) -> (u32, u32) {
+    let g = gcd(num, den).max(1);
+    let (mut num, mut den) = (num / g, den / g);
+    let max = num.max(den);
+    if max > u64::from(u32::MAX) {
+        let scale = max.div_ceil(u64::from(u32::MAX));
+        num = (num / scale).max(1);
+        den = (den / scale).max(1);
+    }
+    (num as u32, den as u32)
+}
+
+/// Length of an interval fraction, in seconds
+fn secs((num, den): (u32, u32)) -> f64 {
+    num as f64 / den as f64
+}
+
+/// Pick the frame interval (in seconds, as a fraction) closest to `preferred_fps`.
+///
+/// Without a preference, the first discrete interval or the fastest stepwise one is picked.
+pub(crate) fn pick_interval(info: &IntervalInfo, preferred_fps: Option<f64>) -> Result<(u32, u32)> {
+    match info {
+        IntervalInfo::Discretes(v) => {
+            let interval = match preferred_fps {
+                Some(fps) => v.iter().min_by(|a, b| {
+                    let diff = |i| (1.0 / secs(i) - fps).abs();
+                    diff(**a).total_cmp(&diff(**b))
+                }),
+                None => v.first(),
+            };
+            interval
+                .copied()
+                .ok_or_else(|| anyhow!("no intervals! {v:?}"))
+        }
+        IntervalInfo::Stepwise { min, max, step } => {
+            let Some(fps) = preferred_fps else {
+                return Ok(*min);
+            };
+            // min + k * step, as an exact fraction
+            let (min_secs, max_secs) = (secs(*min), secs(*max));
+            let target = (1.0 / fps).clamp(min_secs, max_secs);
+            let step_secs = secs(*step);
+            let k = if step_secs > 0.0 {
+                ((target - min_secs) / step_secs).round() as u32
+            } else {
+                0
+            };
+            // in u64, as drivers may report intervals in units as small as 100ns
+            let [min_num, min_den, step_num, step_den] =
+                [min.0, min.1, step.0, step.1].map(u64::from);
+            let den = min_den * step_den;
+            let mut num = min_num * step_den + u64::from(k) * step_num * min_den;
+            if k > 0 && num as f64 / den as f64 > max_secs {
+                num -= step_num * min_den;
+            }
+            Ok(reduce(num, den))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discrete_resolution() {
+        let info = ResolutionInfo::Discretes(vec![(1920, 1080), (640, 360), (320, 180)]);
+        assert_eq!(pick_resolution(&info, None).unwrap(), (1920, 1080));
+        assert_eq!(
+            pick_resolution(&info, Some((600, 400))).unwrap(),
+            (640, 360)
+        );
+        assert!(pick_resolution(&ResolutionInfo::Discretes(vec![]), None).is_err());
+    }
+
+    #[test]
+    fn stepwise_resolution() {
+        let info = ResolutionInfo::Stepwise {
+            min: (160, 120),
+            max: (1280, 720),
+            step: (16, 8),
+        };
+        assert_eq!(pick_resolution(&info, None).unwrap(), (160, 120));
+        assert_eq!(
+            pick_resolution(&info, Some((650, 365))).unwrap(),
+            (656, 368)
+        );
+        assert_eq!(
+            pick_resolution(&info, Some((4000, 10))).unwrap(),
+            (1280, 120)
+        );
+    }
+
+    #[test]
+    fn discrete_interval() {
+        let info = IntervalInfo::Discretes(vec![(1, 5), (1, 15), (1, 30)]);
+        assert_eq!(pick_interval(&info, None).unwrap(), (1, 5));
+        assert_eq!(pick_interval(&info, Some(25.0)).unwrap(), (1, 30));
+    }
+
+    #[test]
+    fn stepwise_interval() {
+        let info = IntervalInfo::Stepwise {
+            min: (1, 30),
+            max: (1, 1),
+            step: (1, 30),
+        };
+        assert_eq!(pick_interval(&info, None).unwrap(), (1, 30));
+        // 1/10s is 3 steps of 1/30s
+        let interval = pick_interval(&info, Some(10.0)).unwrap();
+        assert!((secs(interval) - 0.1).abs() < 1e-9, "{interval:?}");
+        let interval = pick_interval(&info, Some(100.0)).unwrap();
+        assert!((secs(interval) - 1.0 / 30.0).abs() < 1e-9, "{interval:?}");
+    }
+
+    #[test]
+    fn stepwise_interval_fine_units() {
+        // in microseconds
+        let info = IntervalInfo::Stepwise {
+            min: (33333, 1000000),
+            max: (1000000, 1000000),
+            step: (1, 1000000),
+        };
+        assert_eq!(pick_interval(&info, Some(10.0)).unwrap(), (1, 10));
+        // in 100ns units
+        let info = IntervalInfo::Stepwise {
+            min: (333333, 10000000),
+            max: (10000000, 10000000),
+            step: (1, 10000000),
+        };
+        assert_eq!(pick_interval(&info, Some(4.0)).unwrap(), (1, 4));
+        // too big for u32 even when reduced
+        let approx = reduce(u64::MAX, u64::MAX / 2);
+        assert!((secs(approx) - 2.0).abs() < 1e-6, "{approx:?}");
+    }
+}
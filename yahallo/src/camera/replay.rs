@@ -0,0 +1,192 @@
+//! Record frames from a source to disk, and play them back later.
+//!
+//! A session is a directory with one file per raw frame, and a `session.json`
+//! with the metadata needed to play them back at the original cadence.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+
+use super::{Frame, FrameSource};
+
+/// Name of the metadata file in a session dir
+pub const SESSION_FILE: &str = "session.json";
+
+/// Capture `frames` frames from `src` into the session dir `out`.
+pub fn record(src: &mut dyn FrameSource, frames: usize, out: &Path) -> Result<()> {
+    std::fs::create_dir_all(out).with_context(|| format!("couldn't create {}", out.display()))?;
+    let mut resolution = None;
+    let mut format = None;
+    let mut entries = vec![];
+    let start = Instant::now();
+    for i in 0..frames {
+        let frame = src.capture()?;
+        let timestamp = start.elapsed();
+        if *resolution.get_or_insert(frame.resolution) != frame.resolution
+            || *format.get_or_insert(frame.format) != frame.format
+        {
+            bail!("Frame format changed mid-session");
+        }
+        let file = format!("frame_{i:05}.raw");
+        std::fs::write(out.join(&file), &*frame)
+            .with_context(|| format!("couldn't write {file}"))?;
+        entries.push(json!({
+            "file": file,
+            "timestamp_us": timestamp.as_micros() as u64,
+        }));
+    }
+    let (Some(resolution), Some(format)) = (resolution, format) else {
+        bail!("No frames recorded");
+    };
+    let meta = json!({
+        "resolution": [resolution.0, resolution.1],
+        "format": String::from_utf8_lossy(&format),
+        "interval_us": src.interval().as_micros() as u64,
+        "frames": entries,
+    });
+    let path = out.join(SESSION_FILE);
+    let f = File::create(&path).with_context(|| format!("file {}", path.display()))?;
+    serde_json::to_writer_pretty(BufWriter::new(f), &meta)?;
+    Ok(())
+}
+
+struct ReplayFrame {
+    path: PathBuf,
+    /// Time since the start of the session
+    timestamp: Duration,
+}
+
+/// Plays back a recorded session at its original cadence.
+///
+/// Loops back to the first frame after the last one.
+pub struct Replay {
+    resolution: (u32, u32),
+    format: [u8; 4],
+    interval: Duration,
+    frames: Vec<ReplayFrame>,
+    next: usize,
+    /// When the current loop of the session started playing
+    started: Option<Instant>,
+}
+
+impl Replay {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let path = dir.join(SESSION_FILE);
+        let f = File::open(&path).with_context(|| format!("couldn't open {}", path.display()))?;
+        let v: serde_json::Value = serde_json::from_reader(BufReader::new(f))
+            .with_context(|| anyhow!("Failed to read json at {}", path.display()))?;
+        let dim = |i: usize| -> Result<u32> {
+            v["resolution"][i]
+                .as_u64()
+                .and_then(|d| d.try_into().ok())
+                .ok_or_else(|| anyhow!("invalid 'resolution' in {}", path.display()))
+        };
+        let resolution = (dim(0)?, dim(1)?);
+        let format = v["format"]
+            .as_str()
+            .and_then(|f| f.as_bytes().try_into().ok())
+            .ok_or_else(|| anyhow!("invalid 'format' in {}", path.display()))?;
+        let interval = v["interval_us"]
+            .as_u64()
+            .map(Duration::from_micros)
+            .ok_or_else(|| anyhow!("invalid 'interval_us' in {}", path.display()))?;
+        let frames = v["frames"]
+            .as_array()
+            .ok_or_else(|| anyhow!("invalid 'frames' in {}", path.display()))?
+            .iter()
+            .map(|f| {
+                Ok(ReplayFrame {
+                    path: dir.join(
+                        f["file"]
+                            .as_str()
+                            .ok_or_else(|| anyhow!("invalid 'file' in {f}"))?,
+                    ),
+                    timestamp: Duration::from_micros(
+                        f["timestamp_us"]
+                            .as_u64()
+                            .ok_or_else(|| anyhow!("invalid 'timestamp_us' in {f}"))?,
+                    ),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if frames.is_empty() {
+            bail!("No frames in {}", path.display());
+        }
+        Ok(Self {
+            resolution,
+            format,
+            interval,
+            frames,
+            next: 0,
+            started: None,
+        })
+    }
+}
+
+impl FrameSource for Replay {
+    fn capture(&mut self) -> Result<Frame> {
+        let frame = &self.frames[self.next];
+        // The first frame is returned right away, the rest are delayed relative to it
+        let started = *self
+            .started
+            .get_or_insert_with(|| Instant::now() - frame.timestamp);
+        let due = started + frame.timestamp;
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        let data = std::fs::read(&frame.path)
+            .with_context(|| format!("couldn't read {}", frame.path.display()))?;
+
+        self.next += 1;
+        if self.next == self.frames.len() {
+            self.next = 0;
+            self.started = None;
+        }
+        Ok(Frame::new(self.resolution, self.format, data))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn resolution(&self) -> Result<(NonZeroU32, NonZeroU32)> {
+        let (w, h) = self.resolution;
+        Ok((w.try_into()?, h.try_into()?))
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::RawDump;
+
+    #[test]
+    fn record_and_replay() {
+        let dir = crate::utils::temp_dir("replay");
+        let dump = dir.join("frames.gray");
+        std::fs::write(&dump, [1, 1, 2, 2, 3, 3]).unwrap();
+        let mut src = RawDump::open(&dump, (2, 1), Duration::from_millis(10)).unwrap();
+        let session = dir.join("session");
+        record(&mut src, 2, &session).unwrap();
+
+        let mut replay = Replay::open(&session).unwrap();
+        let frames = (0..3)
+            .map(|_| replay.capture().unwrap())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replay.interval(), Duration::from_millis(10));
+        assert_eq!(frames[0].resolution, (2, 1));
+        assert_eq!(&frames[0].format, b"GREY");
+        assert_eq!(&*frames[0], &[1, 1]);
+        assert_eq!(&*frames[1], &[2, 2]);
+        assert_eq!(&*frames[2], &[1, 1]);
+    }
+}
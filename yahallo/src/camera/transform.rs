@@ -0,0 +1,111 @@
+//! Turning frames upright, for cameras mounted sideways or upside down.
+
+use anyhow::{bail, Result};
+use image::{imageops, GenericImageView, GrayImage, Luma};
+
+/// Mirroring of the frame
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Flip {
+    #[default]
+    None,
+    /// Left to right
+    Horizontal,
+    /// Top to bottom
+    Vertical,
+}
+
+impl Flip {
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "horizontal" => Self::Horizontal,
+            "vertical" => Self::Vertical,
+            _ => bail!("expected one of none, horizontal, vertical, found {s}"),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Horizontal => "horizontal",
+            Self::Vertical => "vertical",
+        }
+    }
+}
+
+/// Rotation and flip applied to each frame, in that order
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Transform {
+    /// Clockwise, in degrees. One of 0, 90, 180 or 270.
+    rotate: u32,
+    flip: Flip,
+}
+
+impl Transform {
+    pub fn new(rotate: u32, flip: Flip) -> Result<Self> {
+        if ![0, 90, 180, 270].contains(&rotate) {
+            bail!("rotation should be one of 0, 90, 180, 270, found {rotate}");
+        }
+        Ok(Self { rotate, flip })
+    }
+
+    pub fn rotate(&self) -> u32 {
+        self.rotate
+    }
+
+    pub fn flip(&self) -> Flip {
+        self.flip
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.rotate == 0 && self.flip == Flip::None
+    }
+
+    /// Size of a `(width, height)` frame after the transform
+    pub fn apply_dims(&self, (w, h): (u32, u32)) -> (u32, u32) {
+        match self.rotate {
+            90 | 270 => (h, w),
+            _ => (w, h),
+        }
+    }
+
+    pub(crate) fn apply(&self, img: &impl GenericImageView<Pixel = Luma<u8>>) -> GrayImage {
+        let mut out = match self.rotate {
+            90 => imageops::rotate90(img),
+            180 => imageops::rotate180(img),
+            270 => imageops::rotate270(img),
+            _ => GrayImage::from_fn(img.width(), img.height(), |x, y| img.get_pixel(x, y)),
+        };
+        match self.flip {
+            Flip::None => {}
+            Flip::Horizontal => imageops::flip_horizontal_in_place(&mut out),
+            Flip::Vertical => imageops::flip_vertical_in_place(&mut out),
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_and_flip() {
+        // 1 2 3
+        // 4 5 6
+        let img = GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        let apply = |rotate, flip| {
+            let t = Transform::new(rotate, flip).unwrap();
+            let out = t.apply(&img);
+            assert_eq!(out.dimensions(), t.apply_dims(img.dimensions()));
+            out.into_raw()
+        };
+        assert_eq!(apply(0, Flip::None), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(apply(90, Flip::None), [4, 1, 5, 2, 6, 3]);
+        assert_eq!(apply(180, Flip::None), [6, 5, 4, 3, 2, 1]);
+        assert_eq!(apply(270, Flip::None), [3, 6, 2, 5, 1, 4]);
+        assert_eq!(apply(0, Flip::Horizontal), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(apply(90, Flip::Vertical), [6, 3, 5, 2, 4, 1]);
+        assert!(Transform::new(45, Flip::None).is_err());
+    }
+}
@@ -1,43 +1,624 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use toml::Value;
 
-#[derive(Debug)]
+use crate::camera::emitter::EmitterControl;
+use crate::camera::transform::{Flip, Transform};
+use crate::camera::{self, format, CameraSpec};
+
+/// Where the config file is read from, unless specified otherwise.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/yahallo/config.toml";
+/// Name of the drop-in directory, relative to the directory of the config file.
+const DROPIN_DIR: &str = "config.d";
+/// Prefix for environment variables overriding config keys.
+const ENV_PREFIX: &str = "YAHALLO_";
+
+/// All the keys that can be set in the config.
+const KEYS: &[&str] = &[
+    "camera",
+    "dlib_model_dir",
+    "models_dir",
+    "faces_file",
+    "match_threshold",
+    "match_margin",
+    "match_frames",
+    "match_window",
+    "dark_threshold",
+    "timeout",
+    "face_selection",
+    "blink_liveness",
+    "blink_threshold",
+    "spoof_threshold",
+    "camera_formats",
+    "camera_resolution",
+    "camera_fps",
+    "camera_warmup_frames",
+    "camera_warmup_tolerance",
+    "camera_exposure",
+    "camera_gain",
+    "camera_brightness",
+    "camera_rotate",
+    "camera_flip",
+    "camera_idle_timeout",
+    "emitter",
+];
+
+/// Keys that can be overridden for a particular user, in a `[user.<name>]` table.
+const USER_KEYS: &[&str] = &["camera", "match_threshold", "dark_threshold", "timeout"];
+
+/// Where the effective value of a config key came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(String),
+    CommandLine,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env(var) => write!(f, "env {var}"),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// Which face to use when there are several in the frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaceSelection {
+    /// The biggest one, which is usually the closest to the camera
+    Largest,
+    /// The one closest to the center of the frame
+    Central,
+    /// Fail with [`crate::Error::MultipleFaces`]
+    Reject,
+    /// Try each of them, largest first
+    All,
+}
+
+impl FaceSelection {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "largest" => Self::Largest,
+            "central" => Self::Central,
+            "reject" => Self::Reject,
+            "all" => Self::All,
+            _ => bail!("expected one of largest, central, reject, all, found {s}"),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Largest => "largest",
+            Self::Central => "central",
+            Self::Reject => "reject",
+            Self::All => "all",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
-    camera_path: PathBuf,
+    /// Cameras to try, in order
+    cameras: Vec<CameraSpec>,
     // Could use the "embed-nn" feature of dlib to avoid this.
     // To get a completely independent binary, we would also have to enable the "build-native" flag of dlib
     dlib_model_dir: PathBuf,
+    /// Where the faces of each user are stored
+    models_dir: PathBuf,
+    /// The single faces file of older versions, only read by `yahallo migrate`
     faces_file: PathBuf,
     /// Euclidean distance
     pub(crate) match_threshold: f64,
+    /// How much closer the match should be than the closest face of anyone else
+    match_margin: f64,
+    /// How many of the last `match_window` frames with a face should match
+    match_frames: u32,
+    match_window: u32,
     /// maximum percent of dark pixels in frame to allow face recog
     dark_threshold: u32,
+    /// How long to wait for a matching face
+    timeout: Duration,
+    /// Which face to use when there are several in the frame
+    face_selection: FaceSelection,
+    /// Whether the user should blink before a match is accepted
+    blink_liveness: bool,
+    /// Eye aspect ratio at or below which the eyes count as closed
+    blink_threshold: f64,
+    /// Faces scoring lower than this look like a screen or print to an IR camera
+    spoof_threshold: f64,
+    /// Pixel formats to request from the camera, in order of preference
+    camera_formats: Vec<[u8; 4]>,
+    /// Resolution to request from the camera, the closest available one is used
+    camera_resolution: Option<(u32, u32)>,
+    /// Frame rate to request from the camera, the closest available one is used
+    camera_fps: Option<f64>,
+    /// Maximum number of frames to discard after starting the camera
+    camera_warmup_frames: u32,
+    /// Change in mean brightness between frames below which the exposure counts as settled
+    camera_warmup_tolerance: f64,
+    /// Absolute exposure time, in units of 100µs. Turns off auto exposure.
+    camera_exposure: Option<i32>,
+    camera_gain: Option<i32>,
+    camera_brightness: Option<i32>,
+    /// Turns the frames upright
+    camera_transform: Transform,
+    /// How long the daemon keeps the camera open after a request, zero to close it right away
+    camera_idle_timeout: Duration,
+    /// UVC controls that turn on the IR emitter
+    emitter: Vec<EmitterControl>,
+    /// Overrides for particular users
+    users: BTreeMap<String, UserOverrides>,
+    /// Source of each key that was not left at its default
+    sources: BTreeMap<String, Source>,
+}
+
+/// Subset of the config that can be changed per user.
+#[derive(Debug, Clone, Default)]
+struct UserOverrides {
+    cameras: Option<Vec<CameraSpec>>,
+    match_threshold: Option<f64>,
+    dark_threshold: Option<u32>,
+    timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            cameras: vec![CameraSpec::Path(PathBuf::from("/dev/video2"))],
+            dlib_model_dir: PathBuf::from("/etc/yahallo/data"),
+            models_dir: PathBuf::from("/var/lib/yahallo/models"),
+            faces_file: PathBuf::from("/etc/yahallo/data/faces.json"),
+            match_threshold: 0.6,
+            match_margin: 0.0,
+            match_frames: 2,
+            match_window: 3,
+            dark_threshold: 100,
+            timeout: Duration::from_secs(2),
+            face_selection: FaceSelection::Largest,
+            blink_liveness: false,
+            blink_threshold: 0.2,
+            spoof_threshold: 0.0,
+            camera_formats: format::SUPPORTED_FORMATS.to_vec(),
+            camera_resolution: None,
+            camera_fps: None,
+            camera_warmup_frames: 10,
+            camera_warmup_tolerance: 2.0,
+            camera_exposure: None,
+            camera_gain: None,
+            camera_brightness: None,
+            camera_transform: Transform::default(),
+            camera_idle_timeout: Duration::ZERO,
+            emitter: vec![],
+            users: BTreeMap::new(),
+            sources: BTreeMap::new(),
+        }
+    }
 }
 
 impl Config {
-    pub fn new(
-        camera_path: PathBuf,
-        dlib_model_dir: PathBuf,
-        faces_file: PathBuf,
-        match_threshold: f64,
-        dark_threshold: u32,
-    ) -> anyhow::Result<Self> {
-        if faces_file.is_dir() {
-            bail!("Faces file should not be a dir!");
-        }
-        if dark_threshold > 100 {
-            bail!("Dark threshold percent should be 0..=100");
-        }
-        Ok(Self {
-            camera_path,
-            dlib_model_dir,
-            faces_file,
-            match_threshold,
-            dark_threshold,
+    /// Read the config from [`DEFAULT_CONFIG_PATH`].
+    ///
+    /// Falls back to the defaults if the file does not exist.
+    /// See [`Config::from_file`] for how the rest of the sources are merged.
+    pub fn load() -> Result<Self> {
+        let path = Path::new(DEFAULT_CONFIG_PATH);
+        let mut config = Self::default();
+        if path.exists() {
+            config.merge_file(path)?;
+        } else {
+            info!("{} not found, using default config", path.display());
+        }
+        config.merge_rest(path)?;
+        Ok(config)
+    }
+
+    /// Read the config from `path` if given, else from [`DEFAULT_CONFIG_PATH`].
+    pub fn load_from(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::from_file(path),
+            None => Self::load(),
+        }
+    }
+
+    /// Read the config from a TOML file.
+    ///
+    /// Keys not present in the file take their default values.
+    /// Relative paths are resolved against the directory containing the file.
+    ///
+    /// On top of this, the `*.toml` files in the `config.d` directory next to it
+    /// are merged in lexical order, followed by `YAHALLO_<KEY>` environment variables.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config = Self::default();
+        config.merge_file(path)?;
+        config.merge_rest(path)?;
+        Ok(config)
+    }
+
+    /// Merge the drop-ins and env vars, then validate the final result.
+    fn merge_rest(&mut self, path: &Path) -> Result<()> {
+        self.merge_dropins(&path.with_file_name(DROPIN_DIR))?;
+        self.merge_env()?;
+        self.validate()
+    }
+
+    fn merge_file(&mut self, path: &Path) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new("/"));
+        self.merge_toml(&contents, base_dir, Source::File(path.to_owned()))
+            .with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    fn merge_dropins(&mut self, dir: &Path) -> Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("couldn't read {}", dir.display()));
+            }
+        };
+        let mut files = entries
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .with_context(|| format!("couldn't read {}", dir.display()))?;
+        files.retain(|f| f.extension().is_some_and(|ext| ext == "toml"));
+        files.sort();
+        for file in files {
+            self.merge_file(&file)?;
+        }
+        Ok(())
+    }
+
+    fn merge_env(&mut self) -> Result<()> {
+        for key in KEYS {
+            let var = format!("{ENV_PREFIX}{}", key.to_uppercase());
+            let Ok(val) = std::env::var(&var) else {
+                continue;
+            };
+            self.set_env(key, &val, Source::Env(var.clone()))
+                .with_context(|| format!("invalid env var {var}"))?;
+        }
+        Ok(())
+    }
+
+    /// Set a key from the string value of an env var.
+    ///
+    /// The type is guessed from the value, so a path like `2024` is tried as a string too.
+    fn set_env(&mut self, key: &str, val: &str, source: Source) -> Result<()> {
+        let value = env_value(val);
+        // relative paths stay relative to the working dir
+        match self.set(key, &value, Path::new(""), source.clone()) {
+            Err(e) if !value.is_str() => {
+                let string = Value::String(val.to_owned());
+                // the error for the guessed type is the more helpful one
+                self.set(key, &string, Path::new(""), source).map_err(|_| e)
+            }
+            res => res,
+        }
+    }
+
+    fn merge_toml(&mut self, contents: &str, base_dir: &Path, source: Source) -> Result<()> {
+        let table: toml::Table = contents.parse()?;
+        for (key, value) in &table {
+            self.set(key, value, base_dir, source.clone())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn parse(contents: &str, base_dir: &Path) -> Result<Self> {
+        let mut config = Self::default();
+        config.merge_toml(contents, base_dir, Source::Default)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Set a single key from its TOML value.
+    fn set(&mut self, key: &str, value: &Value, base_dir: &Path, source: Source) -> Result<()> {
+        let res = match key {
+            "camera" => as_cameras(value, base_dir).map(|v| self.cameras = v),
+            "dlib_model_dir" => as_path(value, base_dir).map(|v| self.dlib_model_dir = v),
+            "models_dir" => as_path(value, base_dir).map(|v| self.models_dir = v),
+            "faces_file" => as_path(value, base_dir).map(|v| self.faces_file = v),
+            "match_threshold" => as_f64(value).map(|v| self.match_threshold = v),
+            "match_margin" => as_f64(value).map(|v| self.match_margin = v),
+            "match_frames" => as_u32(value).map(|v| self.match_frames = v),
+            "match_window" => as_u32(value).map(|v| self.match_window = v),
+            "dark_threshold" => as_u32(value).map(|v| self.dark_threshold = v),
+            "timeout" => as_duration(value).map(|v| self.timeout = v),
+            "face_selection" => as_face_selection(value).map(|v| self.face_selection = v),
+            "blink_liveness" => as_bool(value).map(|v| self.blink_liveness = v),
+            "blink_threshold" => as_f64(value).map(|v| self.blink_threshold = v),
+            "spoof_threshold" => as_f64(value).map(|v| self.spoof_threshold = v),
+            "camera_formats" => as_formats(value).map(|v| self.camera_formats = v),
+            "camera_resolution" => as_resolution(value).map(|v| self.camera_resolution = Some(v)),
+            "camera_fps" => as_f64(value).map(|v| self.camera_fps = Some(v)),
+            "camera_warmup_frames" => as_u32(value).map(|v| self.camera_warmup_frames = v),
+            "camera_warmup_tolerance" => as_f64(value).map(|v| self.camera_warmup_tolerance = v),
+            "camera_exposure" => as_i32(value).map(|v| self.camera_exposure = Some(v)),
+            "camera_gain" => as_i32(value).map(|v| self.camera_gain = Some(v)),
+            "camera_brightness" => as_i32(value).map(|v| self.camera_brightness = Some(v)),
+            "camera_rotate" => as_u32(value)
+                .and_then(|v| Transform::new(v, self.camera_transform.flip()))
+                .map(|v| self.camera_transform = v),
+            "camera_flip" => as_flip(value)
+                .and_then(|v| Transform::new(self.camera_transform.rotate(), v))
+                .map(|v| self.camera_transform = v),
+            "camera_idle_timeout" => as_duration(value).map(|v| self.camera_idle_timeout = v),
+            "emitter" => as_emitter(value).map(|v| self.emitter = v),
+            "user" => return self.set_users(value, base_dir, source),
+            _ => {
+                warn!("Ignoring unknown config key `{key}` in {source}");
+                return Ok(());
+            }
+        };
+        res.with_context(|| format!("`{key}`"))?;
+        self.sources.insert(key.to_owned(), source);
+        Ok(())
+    }
+
+    /// Set the per-user overrides from the `user` table.
+    fn set_users(&mut self, value: &Value, base_dir: &Path, source: Source) -> Result<()> {
+        let users = value
+            .as_table()
+            .ok_or_else(|| anyhow!("`user`: expected a table, found {}", value.type_str()))?;
+        for (user, table) in users {
+            let table = table.as_table().ok_or_else(|| {
+                anyhow!(
+                    "`user.{user}`: expected a table, found {}",
+                    table.type_str()
+                )
+            })?;
+            let overrides = self.users.entry(user.clone()).or_default();
+            for (key, value) in table {
+                let res = match key.as_str() {
+                    "camera" => as_cameras(value, base_dir).map(|v| overrides.cameras = Some(v)),
+                    "match_threshold" => as_f64(value).map(|v| overrides.match_threshold = Some(v)),
+                    "dark_threshold" => as_u32(value).map(|v| overrides.dark_threshold = Some(v)),
+                    "timeout" => as_duration(value).map(|v| overrides.timeout = Some(v)),
+                    _ => {
+                        warn!("Ignoring unknown config key `user.{user}.{key}` in {source}");
+                        continue;
+                    }
+                };
+                res.with_context(|| format!("`user.{user}.{key}`"))?;
+                self.sources
+                    .insert(format!("user.{user}.{key}"), source.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the config with the overrides for `user` applied.
+    pub fn for_user(&self, user: &str) -> Config {
+        let mut config = self.clone();
+        let Some(overrides) = self.users.get(user) else {
+            return config;
+        };
+        if let Some(cameras) = &overrides.cameras {
+            config.cameras = cameras.clone();
+        }
+        if let Some(match_threshold) = overrides.match_threshold {
+            config.match_threshold = match_threshold;
+        }
+        if let Some(dark_threshold) = overrides.dark_threshold {
+            config.dark_threshold = dark_threshold;
+        }
+        if let Some(timeout) = overrides.timeout {
+            config.timeout = timeout;
+        }
+        for key in USER_KEYS {
+            if let Some(source) = self.sources.get(&format!("user.{user}.{key}")) {
+                config.sources.insert(key.to_string(), source.clone());
+            }
+        }
+        config
+    }
+
+    /// Get the effective value of a key, as it would be written in the config file.
+    fn get(&self, key: &str) -> Option<Value> {
+        let path_val = |p: &Path| Value::String(p.display().to_string());
+        Some(match key {
+            "camera" => match self.cameras.as_slice() {
+                [camera] => Value::String(camera.to_string()),
+                cameras => Value::Array(
+                    cameras
+                        .iter()
+                        .map(|c| Value::String(c.to_string()))
+                        .collect(),
+                ),
+            },
+            "dlib_model_dir" => path_val(&self.dlib_model_dir),
+            "models_dir" => path_val(&self.models_dir),
+            "faces_file" => path_val(&self.faces_file),
+            "match_threshold" => Value::Float(self.match_threshold),
+            "match_margin" => Value::Float(self.match_margin),
+            "match_frames" => Value::Integer(self.match_frames.into()),
+            "match_window" => Value::Integer(self.match_window.into()),
+            "dark_threshold" => Value::Integer(self.dark_threshold.into()),
+            "timeout" => Value::String(humantime::format_duration(self.timeout).to_string()),
+            "face_selection" => Value::String(self.face_selection.as_str().to_owned()),
+            "blink_liveness" => Value::Boolean(self.blink_liveness),
+            "blink_threshold" => Value::Float(self.blink_threshold),
+            "spoof_threshold" => Value::Float(self.spoof_threshold),
+            "camera_formats" => Value::Array(
+                self.camera_formats
+                    .iter()
+                    .map(|f| Value::String(format::fourcc_str(f).into_owned()))
+                    .collect(),
+            ),
+            "camera_resolution" => {
+                let (w, h) = self.camera_resolution?;
+                Value::Array(vec![Value::Integer(w.into()), Value::Integer(h.into())])
+            }
+            "camera_fps" => Value::Float(self.camera_fps?),
+            "camera_warmup_frames" => Value::Integer(self.camera_warmup_frames.into()),
+            "camera_warmup_tolerance" => Value::Float(self.camera_warmup_tolerance),
+            "camera_exposure" => Value::Integer(self.camera_exposure?.into()),
+            "camera_gain" => Value::Integer(self.camera_gain?.into()),
+            "camera_brightness" => Value::Integer(self.camera_brightness?.into()),
+            "camera_rotate" => Value::Integer(self.camera_transform.rotate().into()),
+            "camera_flip" => Value::String(self.camera_transform.flip().as_str().to_owned()),
+            "camera_idle_timeout" => {
+                Value::String(humantime::format_duration(self.camera_idle_timeout).to_string())
+            }
+            "emitter" => Value::Array(self.emitter.iter().map(|c| c.to_toml()).collect()),
+            _ => return None,
         })
     }
 
+    /// Whether `key` is overridden in the `[user.<name>]` table of `user`.
+    pub fn is_user_override(&self, user: &str, key: &str) -> bool {
+        self.sources.contains_key(&format!("user.{user}.{key}"))
+    }
+
+    /// Where the effective value of `key` came from.
+    pub fn source(&self, key: &str) -> &Source {
+        self.sources.get(key).unwrap_or(&Source::Default)
+    }
+
+    /// List each key with its effective value and the source of that value.
+    pub fn explain(&self) -> Vec<(&'static str, Value, &Source)> {
+        KEYS.iter()
+            .filter_map(|&key| Some((key, self.get(key)?, self.source(key))))
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.validate_values()?;
+        for user in self.users.keys() {
+            self.for_user(user)
+                .validate_values()
+                .with_context(|| format!("`user.{user}`"))?;
+        }
+        Ok(())
+    }
+
+    fn validate_values(&self) -> Result<()> {
+        if self.models_dir.is_file() {
+            bail!("`models_dir` should be a dir!");
+        }
+        if !(self.match_threshold.is_finite() && self.match_threshold > 0.0) {
+            bail!(
+                "`match_threshold` should be a positive number, got {}",
+                self.match_threshold
+            );
+        }
+        if !(self.match_margin.is_finite() && self.match_margin >= 0.0) {
+            bail!(
+                "`match_margin` should not be negative, got {}",
+                self.match_margin
+            );
+        }
+        if !(self.blink_threshold > 0.0 && self.blink_threshold < 1.0) {
+            bail!(
+                "`blink_threshold` should be between 0 and 1, got {}",
+                self.blink_threshold
+            );
+        }
+        if !(0.0..=1.0).contains(&self.spoof_threshold) {
+            bail!(
+                "`spoof_threshold` should be between 0 and 1, got {}",
+                self.spoof_threshold
+            );
+        }
+        if self.match_frames == 0 || self.match_frames > self.match_window {
+            bail!(
+                "`match_frames` should be between 1 and `match_window` ({}), got {}",
+                self.match_window,
+                self.match_frames
+            );
+        }
+        if self.cameras.is_empty() {
+            bail!("`camera` should not be empty");
+        }
+        if self.camera_formats.is_empty() {
+            bail!("`camera_formats` should not be empty");
+        }
+        if self
+            .camera_resolution
+            .is_some_and(|(w, h)| w == 0 || h == 0)
+        {
+            bail!("`camera_resolution` should not be zero");
+        }
+        if self
+            .camera_fps
+            .is_some_and(|fps| !(fps.is_finite() && fps > 0.0))
+        {
+            bail!("`camera_fps` should be a positive number");
+        }
+        if !(self.camera_warmup_tolerance.is_finite() && self.camera_warmup_tolerance >= 0.0) {
+            bail!("`camera_warmup_tolerance` should not be negative");
+        }
+        if self.timeout.is_zero() {
+            bail!("`timeout` should be more than zero");
+        }
+        if self.dark_threshold > 100 {
+            bail!(
+                "`dark_threshold` percent should be 0..=100, got {}",
+                self.dark_threshold
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that the paths in the config are usable, without actually opening them.
+    ///
+    /// Returns all the problems found.
+    pub fn check(&self) -> Vec<anyhow::Error> {
+        let mut problems = vec![];
+        let blink_model = self.blink_liveness.then_some(crate::BLINK_MODEL);
+        for model in crate::DLIB_MODELS.iter().copied().chain(blink_model) {
+            if let Err(e) = self.dlib_model_dat(model) {
+                problems.push(e);
+            }
+        }
+        // Fallback cameras may come and go (like a webcam on a dock), so one usable camera is enough
+        let camera_problems = self
+            .cameras
+            .iter()
+            .filter_map(|camera| check_camera(camera).err())
+            .collect::<Vec<_>>();
+        if camera_problems.len() == self.cameras.len() {
+            problems.extend(camera_problems);
+        } else {
+            for e in camera_problems {
+                warn!("{e:#}");
+            }
+        }
+        // the models dir gets created along with any missing parents, so the closest
+        // existing one should be writable
+        let models_path = self
+            .models_dir
+            .ancestors()
+            .map(|p| {
+                if p.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    p
+                }
+            })
+            .find(|p| p.exists())
+            .unwrap_or(Path::new("."));
+        if !is_writable(models_path) {
+            problems.push(anyhow!("{} is not writable", models_path.display()));
+        }
+        problems
+    }
+
     pub(crate) fn dlib_model_dat(&self, filename: &str) -> Result<PathBuf> {
         let file = self.dlib_model_dir.join(filename);
         if !file.exists() {
@@ -47,8 +628,25 @@ impl Config {
         }
     }
 
-    pub fn camera_path(&self) -> &Path {
-        &self.camera_path
+    pub fn dlib_model_dir(&self) -> &Path {
+        &self.dlib_model_dir
+    }
+
+    /// The cameras to try, in order of preference
+    pub fn cameras(&self) -> &[CameraSpec] {
+        &self.cameras
+    }
+
+    /// Override the models dir, for example from a command line flag.
+    pub fn set_models_dir(&mut self, models_dir: PathBuf) -> Result<()> {
+        self.models_dir = models_dir;
+        self.sources
+            .insert("models_dir".to_owned(), Source::CommandLine);
+        self.validate()
+    }
+
+    pub fn models_dir(&self) -> &Path {
+        &self.models_dir
     }
 
     pub fn faces_file(&self) -> &Path {
@@ -58,4 +656,429 @@ impl Config {
     pub fn dark_threshold(&self) -> u32 {
         self.dark_threshold
     }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn match_margin(&self) -> f64 {
+        self.match_margin
+    }
+
+    pub fn match_frames(&self) -> u32 {
+        self.match_frames
+    }
+
+    pub fn match_window(&self) -> u32 {
+        self.match_window
+    }
+
+    pub fn face_selection(&self) -> FaceSelection {
+        self.face_selection
+    }
+
+    pub fn blink_liveness(&self) -> bool {
+        self.blink_liveness
+    }
+
+    pub fn blink_threshold(&self) -> f64 {
+        self.blink_threshold
+    }
+
+    pub fn spoof_threshold(&self) -> f64 {
+        self.spoof_threshold
+    }
+
+    pub fn camera_formats(&self) -> &[[u8; 4]] {
+        &self.camera_formats
+    }
+
+    pub fn camera_resolution(&self) -> Option<(u32, u32)> {
+        self.camera_resolution
+    }
+
+    pub fn camera_fps(&self) -> Option<f64> {
+        self.camera_fps
+    }
+
+    pub fn camera_warmup_frames(&self) -> u32 {
+        self.camera_warmup_frames
+    }
+
+    pub fn camera_warmup_tolerance(&self) -> f64 {
+        self.camera_warmup_tolerance
+    }
+
+    pub fn camera_exposure(&self) -> Option<i32> {
+        self.camera_exposure
+    }
+
+    pub fn camera_gain(&self) -> Option<i32> {
+        self.camera_gain
+    }
+
+    pub fn camera_brightness(&self) -> Option<i32> {
+        self.camera_brightness
+    }
+
+    pub fn camera_transform(&self) -> Transform {
+        self.camera_transform
+    }
+
+    pub fn camera_idle_timeout(&self) -> Duration {
+        self.camera_idle_timeout
+    }
+
+    pub fn emitter(&self) -> &[EmitterControl] {
+        &self.emitter
+    }
+}
+
+/// Whether the current user has write access to the path
+fn is_writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: path is a valid nul-terminated string
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// Guess the type of an env var value, since we don't know the expected type of the key here.
+/// See [`Config::set_env`] for falling back to a string.
+fn env_value(val: &str) -> Value {
+    if let Ok(i) = val.parse() {
+        Value::Integer(i)
+    } else if let Ok(f) = val.parse() {
+        Value::Float(f)
+    } else if let Ok(b) = val.parse() {
+        Value::Boolean(b)
+    } else {
+        Value::String(val.to_owned())
+    }
+}
+
+/// A single camera, or a list of them to try in order
+fn as_cameras(value: &Value, base_dir: &Path) -> Result<Vec<CameraSpec>> {
+    let as_camera = |value: &Value| {
+        let s = value
+            .as_str()
+            .ok_or_else(|| anyhow!("expected a camera, found {}", value.type_str()))?;
+        CameraSpec::parse(s, base_dir)
+    };
+    match value {
+        Value::Array(values) => values.iter().map(as_camera).collect(),
+        value => Ok(vec![as_camera(value)?]),
+    }
+}
+
+/// Make sure the camera exists and looks like something we can read frames from
+fn check_camera(camera: &CameraSpec) -> Result<()> {
+    let path = camera
+        .resolve()
+        .with_context(|| format!("Camera {camera}"))?;
+    let m = std::fs::metadata(&path).with_context(|| format!("Camera {}", path.display()))?;
+    let is_raw_dump = m.is_file() && camera::is_raw_dump(&path);
+    if !(m.file_type().is_char_device() || m.is_dir() || is_raw_dump) {
+        bail!(
+            "Camera {} is neither a character device, a directory nor a raw frames file",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn as_path(value: &Value, base_dir: &Path) -> Result<PathBuf> {
+    let s = value
+        .as_str()
+        .ok_or_else(|| anyhow!("expected a path, found {}", value.type_str()))?;
+    Ok(base_dir.join(s))
+}
+
+fn as_f64(value: &Value) -> Result<f64> {
+    match value {
+        Value::Float(f) => Ok(*f),
+        Value::Integer(i) => Ok(*i as f64),
+        _ => bail!("expected a number, found {}", value.type_str()),
+    }
+}
+
+/// Either a number of seconds, or a human readable string like "1500ms"
+fn as_duration(value: &Value) -> Result<Duration> {
+    let duration = match value {
+        Value::String(s) => humantime::parse_duration(s)?,
+        Value::Float(_) | Value::Integer(_) => Duration::try_from_secs_f64(as_f64(value)?)?,
+        _ => bail!("expected a duration, found {}", value.type_str()),
+    };
+    Ok(duration)
+}
+
+/// Either a list of FourCCs, or a comma separated string of them
+fn as_formats(value: &Value) -> Result<Vec<[u8; 4]>> {
+    match value {
+        Value::String(s) => s
+            .split(',')
+            .map(|f| format::parse_fourcc(f.trim()))
+            .collect(),
+        Value::Array(arr) => arr
+            .iter()
+            .map(|f| {
+                let f = f
+                    .as_str()
+                    .ok_or_else(|| anyhow!("expected a format, found {}", f.type_str()))?;
+                format::parse_fourcc(f)
+            })
+            .collect(),
+        _ => bail!("expected a list of formats, found {}", value.type_str()),
+    }
+}
+
+/// Either `[width, height]` or a string like "640x360"
+fn as_resolution(value: &Value) -> Result<(u32, u32)> {
+    let dims = match value {
+        Value::String(s) => s
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?))),
+        Value::Array(arr) if arr.len() == 2 => as_u32(&arr[0]).ok().zip(as_u32(&arr[1]).ok()),
+        _ => None,
+    };
+    dims.ok_or_else(|| anyhow!("expected [width, height], found {value}"))
+}
+
+/// Tables with the `unit`, `selector` and `data` bytes of each control
+fn as_emitter(value: &Value) -> Result<Vec<EmitterControl>> {
+    let tables = value
+        .as_array()
+        .ok_or_else(|| anyhow!("expected an array of tables, found {}", value.type_str()))?;
+    let as_u8 = |value: &Value| -> Result<u8> {
+        let i = as_u32(value)?;
+        i.try_into()
+            .map_err(|_| anyhow!("{i} is out of range for a byte"))
+    };
+    tables
+        .iter()
+        .map(|table| {
+            let field = |name| {
+                table
+                    .get(name)
+                    .ok_or_else(|| anyhow!("missing `{name}` in {table}"))
+            };
+            let data = field("data")?
+                .as_array()
+                .ok_or_else(|| anyhow!("`data` should be an array of bytes"))?
+                .iter()
+                .map(as_u8)
+                .collect::<Result<Vec<_>>>()?;
+            if data.is_empty() {
+                bail!("`data` should not be empty");
+            }
+            Ok(EmitterControl {
+                unit: as_u8(field("unit")?)?,
+                selector: as_u8(field("selector")?)?,
+                data,
+            })
+        })
+        .collect()
+}
+
+fn as_u32(value: &Value) -> Result<u32> {
+    let i = value
+        .as_integer()
+        .ok_or_else(|| anyhow!("expected an integer, found {}", value.type_str()))?;
+    i.try_into()
+        .map_err(|_| anyhow!("{i} is out of range for an unsigned integer"))
+}
+
+fn as_bool(value: &Value) -> Result<bool> {
+    value
+        .as_bool()
+        .ok_or_else(|| anyhow!("expected a boolean, found {}", value.type_str()))
+}
+
+fn as_face_selection(value: &Value) -> Result<FaceSelection> {
+    let s = value
+        .as_str()
+        .ok_or_else(|| anyhow!("expected a string, found {}", value.type_str()))?;
+    FaceSelection::parse(s)
+}
+
+fn as_flip(value: &Value) -> Result<Flip> {
+    let s = value
+        .as_str()
+        .ok_or_else(|| anyhow!("expected a string, found {}", value.type_str()))?;
+    Flip::parse(s)
+}
+
+fn as_i32(value: &Value) -> Result<i32> {
+    let i = value
+        .as_integer()
+        .ok_or_else(|| anyhow!("expected an integer, found {}", value.type_str()))?;
+    i.try_into()
+        .map_err(|_| anyhow!("{i} is out of range for an integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_for_missing_keys() {
+        let config = Config::parse("match_threshold = 0.5", Path::new("/etc/yahallo")).unwrap();
+        assert_eq!(config.match_threshold, 0.5);
+        assert_eq!(config.dark_threshold, Config::default().dark_threshold);
+        assert_eq!(config.cameras, Config::default().cameras);
+    }
+
+    #[test]
+    fn relative_paths() {
+        let config = Config::parse(
+            "faces_file = \"data/faces.json\"\ncamera = \"/dev/video0\"",
+            Path::new("/etc/yahallo"),
+        )
+        .unwrap();
+        assert_eq!(config.faces_file, Path::new("/etc/yahallo/data/faces.json"));
+        assert_eq!(config.cameras, [CameraSpec::Path("/dev/video0".into())]);
+    }
+
+    #[test]
+    fn camera_list() {
+        let config = Config::parse(
+            "camera = [\"usb:04f2:b6dd\", \"webcam\"]",
+            Path::new("/etc/yahallo"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.cameras,
+            [
+                CameraSpec::Usb {
+                    vendor: 0x04f2,
+                    product: 0xb6dd
+                },
+                CameraSpec::Path("/etc/yahallo/webcam".into())
+            ]
+        );
+        assert_eq!(
+            config.get("camera").unwrap().to_string(),
+            r#"["usb:04f2:b6dd", "/etc/yahallo/webcam"]"#
+        );
+        assert!(Config::parse("camera = []", Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let err = Config::parse("dark_threshold = 150", Path::new("/")).unwrap_err();
+        assert!(format!("{err:#}").contains("`dark_threshold`"), "{err:#}");
+        let err = Config::parse("match_threshold = \"high\"", Path::new("/")).unwrap_err();
+        assert!(format!("{err:#}").contains("`match_threshold`"), "{err:#}");
+    }
+
+    #[test]
+    fn dropins_merged_in_order() {
+        let dir = crate::utils::temp_dir("config");
+        let dropins = dir.join(DROPIN_DIR);
+        std::fs::create_dir_all(&dropins).unwrap();
+        let base = dir.join("config.toml");
+        std::fs::write(&base, "match_threshold = 0.5\ndark_threshold = 10").unwrap();
+        std::fs::write(dropins.join("20-b.toml"), "dark_threshold = 30").unwrap();
+        std::fs::write(dropins.join("10-a.toml"), "dark_threshold = 20").unwrap();
+        std::fs::write(dropins.join("30-ignored.txt"), "dark_threshold = 40").unwrap();
+
+        let config = Config::from_file(&base).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.match_threshold, 0.5);
+        assert_eq!(config.source("match_threshold"), &Source::File(base));
+        assert_eq!(config.dark_threshold, 30);
+        assert_eq!(
+            config.source("dark_threshold"),
+            &Source::File(dropins.join("20-b.toml"))
+        );
+        assert_eq!(config.source("camera"), &Source::Default);
+    }
+
+    #[test]
+    fn emitter_controls() {
+        let config = Config::parse(
+            "[[emitter]]\nunit = 14\nselector = 6\ndata = [1, 3, 2, 0]",
+            Path::new("/"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.emitter(),
+            [EmitterControl {
+                unit: 14,
+                selector: 6,
+                data: vec![1, 3, 2, 0]
+            }]
+        );
+        let err = Config::parse(
+            "[[emitter]]\nunit = 14\nselector = 6\ndata = [256]",
+            Path::new("/"),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("`emitter`"), "{err:#}");
+        assert!(Config::parse("[[emitter]]\nunit = 14\ndata = [1]", Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn user_overrides() {
+        let config = Config::parse(
+            "timeout = 3\n[user.alice]\nmatch_threshold = 0.4\ntimeout = \"500ms\"",
+            Path::new("/"),
+        )
+        .unwrap();
+        assert_eq!(config.timeout(), Duration::from_secs(3));
+        let alice = config.for_user("alice");
+        assert_eq!(alice.match_threshold, 0.4);
+        assert_eq!(alice.timeout(), Duration::from_millis(500));
+        assert_eq!(alice.dark_threshold, config.dark_threshold);
+        let bob = config.for_user("bob");
+        assert_eq!(bob.match_threshold, config.match_threshold);
+        assert!(config.is_user_override("alice", "timeout"));
+        assert!(!config.is_user_override("alice", "dark_threshold"));
+        assert!(!config.is_user_override("bob", "timeout"));
+
+        let err = Config::parse("[user.bob]\ndark_threshold = 101", Path::new("/")).unwrap_err();
+        assert!(format!("{err:#}").contains("`user.bob`"), "{err:#}");
+    }
+
+    #[test]
+    fn raw_dump_camera() {
+        let dir = crate::utils::temp_dir("raw-dump-camera");
+        std::fs::write(dir.join("frames.gray"), [0; 4]).unwrap();
+        std::fs::write(dir.join("frames.txt"), [0; 4]).unwrap();
+        let check = |name: &str| check_camera(&CameraSpec::Path(dir.join(name)));
+        assert!(check("frames.gray").is_ok());
+        assert!(check("frames.txt").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_models_dir_parents() {
+        let dir = crate::utils::temp_dir("missing-models-dir");
+        let mut config = Config::default();
+        config
+            .set_models_dir(dir.join("lib/yahallo/models"))
+            .unwrap();
+        let problems = config.check();
+        assert!(
+            !problems
+                .iter()
+                .any(|e| e.to_string().contains("not writable")),
+            "{problems:?}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn env_values() {
+        let mut config = Config::default();
+        let source = || Source::Env("YAHALLO_TEST".into());
+        config.set_env("models_dir", "2024", source()).unwrap();
+        assert_eq!(config.models_dir, Path::new("2024"));
+        config.set_env("match_frames", "3", source()).unwrap();
+        assert_eq!(config.match_frames, 3);
+        config.set_env("blink_liveness", "true", source()).unwrap();
+        assert!(config.blink_liveness);
+        let err = config.set_env("match_frames", "-1", source()).unwrap_err();
+        assert!(format!("{err:#}").contains("out of range"), "{err:#}");
+    }
 }
@@ -0,0 +1,80 @@
+//! Requiring several frames to match before accepting, so one lucky frame isn't enough.
+
+use std::collections::VecDeque;
+
+use crate::config::Config;
+
+/// Accepts once `required` of the last `window` frames with a face have matched
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    required: usize,
+    window: usize,
+    /// Distance of each recent frame, if it matched. Newest last.
+    recent: VecDeque<Option<f64>>,
+}
+
+impl Consensus {
+    pub fn new(required: usize, window: usize) -> Self {
+        debug_assert!(0 < required && required <= window);
+        Self {
+            required,
+            window,
+            recent: VecDeque::with_capacity(window),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.match_frames() as usize,
+            config.match_window() as usize,
+        )
+    }
+
+    /// Record a frame, with its distance if it matched.
+    ///
+    /// Returns the mean distance of the matching frames, once there are enough of them.
+    pub fn push(&mut self, distance: Option<f64>) -> Option<f64> {
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(distance);
+        let matches = self.matches();
+        if matches < self.required {
+            return None;
+        }
+        Some(self.recent.iter().flatten().sum::<f64>() / matches as f64)
+    }
+
+    /// Number of matching frames in the window
+    pub fn matches(&self) -> usize {
+        self.recent.iter().flatten().count()
+    }
+
+    pub fn required(&self) -> usize {
+        self.required
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_of_three() {
+        let mut c = Consensus::new(2, 3);
+        assert_eq!(c.push(Some(0.4)), None);
+        assert_eq!(c.push(None), None);
+        assert_eq!(c.push(None), None);
+        // the first match fell out of the window
+        assert_eq!(c.push(Some(0.5)), None);
+        assert_eq!(c.push(Some(0.3)), Some(0.4));
+        assert_eq!(c.matches(), 2);
+
+        let mut c = Consensus::new(1, 1);
+        assert_eq!(c.push(Some(0.5)), Some(0.5));
+    }
+}
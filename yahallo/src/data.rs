@@ -1,10 +1,13 @@
-use std::fs::File;
-use std::io::{BufReader, BufWriter, ErrorKind};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, Permissions};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use dlib_face_recognition::FaceEncoding;
+use log::warn;
 use serde_json::json;
 
 type FaceId = u64;
@@ -13,15 +16,25 @@ type FaceId = u64;
 #[allow(dead_code)]
 pub struct ModelData {
     time: SystemTime,
+    /// The user the face belongs to.
+    /// Faces in the old single faces file may have none, until they are migrated.
+    user: Option<String>,
     label: String,
     id: FaceId,
     data: FaceEncoding,
 }
 
 impl ModelData {
-    pub fn new(time: SystemTime, label: String, id: FaceId, data: FaceEncoding) -> Self {
+    pub fn new(
+        time: SystemTime,
+        user: String,
+        label: String,
+        id: FaceId,
+        data: FaceEncoding,
+    ) -> Self {
         Self {
             time,
+            user: Some(user),
             label,
             id,
             data,
@@ -36,6 +49,7 @@ impl ModelData {
                     .ok_or_else(|| anyhow!("invalid 'time' in {v}"))?;
                 std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)
             },
+            user: v["user"].as_str().map(str::to_owned),
             label: v["label"]
                 .as_str()
                 .ok_or_else(|| anyhow!("invalid 'label' in {v}"))?
@@ -73,6 +87,7 @@ impl ModelData {
             .as_secs();
         json!({
             "time": time,
+            "user": self.user,
             "label": self.label,
             "id": self.id,
             "data": self.data.as_ref()
@@ -82,87 +97,502 @@ impl ModelData {
     pub fn encoding(&self) -> &FaceEncoding {
         &self.data
     }
-    
+
     pub fn label(&self) -> &str {
         &self.label
     }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
 }
 
+/// The faces enrolled for each user, stored as `<user>.json` files in the models dir.
+///
+/// Anyone who can write to these can log in as the user, so the dir and files are owned
+/// by root and only accessible by it.
 #[derive(Debug)]
-pub(crate) struct Faces(Vec<ModelData>);
+pub(crate) struct Faces {
+    dir: PathBuf,
+    users: BTreeMap<String, Vec<ModelData>>,
+    /// Users whose file was skipped for its permissions, which must not be overwritten
+    skipped: BTreeSet<String>,
+}
 
 impl Faces {
-    /// Parse the faces.json file
-    pub(crate) fn from_file(path: &Path) -> Result<Self> {
-        let f = match File::open(path) {
-            Ok(f) => f,
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                // make new file
-                std::fs::write(path, "[]")
-                    .with_context(|| format!("couldn't create {}", path.display()))?;
-                return Ok(Self(vec![]));
+    /// Read the faces of all the users in the models dir, creating it if needed
+    pub(crate) fn from_dir(dir: &Path) -> Result<Self> {
+        create_models_dir(dir)?;
+        check_permissions(dir, 0o022)?;
+        let mut users = BTreeMap::new();
+        let mut skipped = BTreeSet::new();
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("couldn't read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
             }
-            r => r.with_context(|| format!("{} not found", path.display()))?,
-        };
-        let rdr = BufReader::new(f);
-        let encs: Vec<serde_json::Value> = serde_json::from_reader(rdr)
-            .with_context(|| anyhow!("Failed to read json at {}", path.display()))?;
+            let Some(user) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if let Err(e) = check_permissions(&path, 0o077) {
+                warn!("Skipping {}: {e:#}", path.display());
+                skipped.insert(user.to_owned());
+                continue;
+            }
+            let mut models = read_models(&path)?;
+            // the file name is what counts
+            for model in &mut models {
+                model.user = Some(user.to_owned());
+            }
+            users.insert(user.to_owned(), models);
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            users,
+            skipped,
+        })
+    }
 
-        Ok(Self(
-            encs.iter()
-                .map(ModelData::from_json)
-                .collect::<Result<Vec<_>>>()?,
-        ))
+    /// Fail if the file of the user was skipped, so saving would lose the faces in it
+    fn check_not_skipped(&self, user: &str) -> Result<()> {
+        if self.skipped.contains(user) {
+            bail!(
+                "not overwriting the faces of {user} in {}, fix its permissions first",
+                user_file(&self.dir, user)?.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the faces of the user to their file, readable only by the owner
+    pub(crate) fn save(&self, user: &str) -> Result<()> {
+        let tmp = self.write_temp(user)?;
+        self.replace(user, &tmp)
     }
 
-    /// Parse the faces.json file
-    pub(crate) fn to_file(&self, path: &Path) -> Result<()> {
+    /// Write the faces of the user to a temp file next to theirs, to be put in its place
+    /// with [`Self::replace`]. This way a crash doesn't leave a truncated file behind.
+    fn write_temp(&self, user: &str) -> Result<PathBuf> {
+        let path = user_file(&self.dir, user)?;
+        self.check_not_skipped(user)?;
+        let models = self.users.get(user).map_or(&[][..], Vec::as_slice);
+        let tmp = path.with_extension("json.tmp");
         let f = File::options()
             .create(true)
             .truncate(true)
             .write(true)
-            .open(path)
-            .with_context(|| format!("file {}", path.display()))?;
-        let writer = BufWriter::new(f);
-        let arr = self.0.iter().map(ModelData::as_json).collect::<Vec<_>>();
-        serde_json::to_writer_pretty(writer, &serde_json::json!(arr))?;
-        println!("written {} faces to {}", self.0.len(), path.display());
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("file {}", tmp.display()))?;
+        // the mode only applies to new files
+        f.set_permissions(Permissions::from_mode(0o600))?;
+        let mut writer = BufWriter::new(f);
+        let arr = models.iter().map(ModelData::as_json).collect::<Vec<_>>();
+        serde_json::to_writer_pretty(&mut writer, &json!(arr))?;
+        writer.flush()?;
+        Ok(tmp)
+    }
+
+    /// Move the temp file from [`Self::write_temp`] over the file of the user
+    fn replace(&self, user: &str, tmp: &Path) -> Result<()> {
+        let path = user_file(&self.dir, user)?;
+        std::fs::rename(tmp, &path)
+            .with_context(|| format!("couldn't write {}", path.display()))?;
+        let count = self.users.get(user).map_or(0, Vec::len);
+        println!("written {count} faces to {}", path.display());
         Ok(())
     }
 
-    pub(crate) fn is_empty(&self) -> bool {
-        // In the future, we can check for a particular user
-        self.0.is_empty()
+    /// Whether no faces were enrolled for the user
+    pub(crate) fn is_empty(&self, user: &str) -> bool {
+        self.users.get(user).is_none_or(Vec::is_empty)
     }
 
-    pub(crate) fn add_face(&mut self, enc: FaceEncoding, label: Option<String>) -> Result<()> {
+    pub(crate) fn add_face(
+        &mut self,
+        enc: FaceEncoding,
+        user: String,
+        label: Option<String>,
+    ) -> Result<()> {
         // TODO: Check if too similar
-        let new_id = self.0.last().map_or(1, |d| d.id + 1);
+        let models = self.users.entry(user.clone()).or_default();
+        let new_id = models.last().map_or(1, |d| d.id + 1);
         let data = ModelData {
             time: SystemTime::now(),
+            user: Some(user),
             label: label.unwrap_or_else(|| format!("Model #{new_id}")),
             id: new_id,
             data: enc,
         };
-        self.0.push(data);
+        models.push(data);
         Ok(())
     }
 
+    /// Compare the encoding against the known faces of `user`, or of anyone if `None`.
+    ///
+    /// `None` if there are no such faces.
     pub(crate) fn check_match(
         &self,
         encoding: &FaceEncoding,
-        threshold: f64,
-    ) -> Option<&ModelData> {
-        log::info!("Checking against {} known faces", self.0.len());
-        self.0
+        user: Option<&str>,
+    ) -> Option<MatchResult<'_>> {
+        let known = self.users.values().flatten();
+        log::info!("Checking against {} known faces", known.clone().count());
+        let distances = known.map(|known| (known, known.encoding().distance(encoding)));
+        let res = MatchResult::new(distances, user)?;
+        log::trace!(target: "enc_match", "Closest: {res}");
+        Some(res)
+    }
+}
+
+/// Parse a JSON file with a list of models
+fn read_models(path: &Path) -> Result<Vec<ModelData>> {
+    let f = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let rdr = BufReader::new(f);
+    let encs: Vec<serde_json::Value> = serde_json::from_reader(rdr)
+        .with_context(|| anyhow!("Failed to read json at {}", path.display()))?;
+    encs.iter().map(ModelData::from_json).collect()
+}
+
+fn create_models_dir(dir: &Path) -> Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("couldn't create {}", dir.display()))
+}
+
+/// Make sure only we, usually root, own the file, and that no one else has the access in `mask`.
+///
+/// The dir only needs to be safe from changes, while the faces are also kept private.
+fn check_permissions(path: &Path, mask: u32) -> Result<()> {
+    let meta =
+        std::fs::metadata(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    // SAFETY: geteuid has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    if meta.uid() != euid {
+        bail!(
+            "{} is owned by uid {}, it should be owned by uid {euid}",
+            path.display(),
+            meta.uid()
+        );
+    }
+    let mode = meta.permissions().mode() & 0o777;
+    if mode & mask != 0 {
+        bail!(
+            "{path} is accessible by other users, fix it with `chmod {:03o} {path}`",
+            mode & !mask,
+            path = path.display()
+        );
+    }
+    Ok(())
+}
+
+/// The file with the faces of the user
+fn user_file(dir: &Path, user: &str) -> Result<PathBuf> {
+    if user.is_empty() || user.starts_with('.') || user.contains(['/', '\0']) {
+        bail!("Invalid user name {user:?}");
+    }
+    Ok(dir.join(format!("{user}.json")))
+}
+
+/// Move the faces from the single faces file of older versions into the models dir.
+///
+/// Faces without a user are given to `default_user`, failing if there is none.
+/// The old file is renamed to `<name>.migrated` afterwards.
+/// Returns the number of faces moved for each user.
+pub fn migrate(
+    from: &Path,
+    models_dir: &Path,
+    default_user: Option<&str>,
+) -> Result<BTreeMap<String, usize>> {
+    let models = read_models(from)?;
+    let unowned = models.iter().filter(|m| m.user.is_none()).count();
+    if unowned > 0 && default_user.is_none() {
+        bail!(
+            "{unowned} faces in {} don't belong to a user, pass the user they belong to",
+            from.display()
+        );
+    }
+    let mut faces = Faces::from_dir(models_dir)?;
+    let mut moved = BTreeMap::new();
+    for mut model in models {
+        let user = model
+            .user
+            .clone()
+            .or_else(|| default_user.map(str::to_owned))
+            .expect("checked above");
+        // ids are per user, so they may clash with the existing faces
+        let existing = faces.users.entry(user.clone()).or_default();
+        model.id = existing.last().map_or(1, |d| d.id + 1);
+        model.user = Some(user.clone());
+        existing.push(model);
+        *moved.entry(user).or_default() += 1;
+    }
+    // write all of them before replacing any, so a failure doesn't leave the faces half
+    // migrated, with a rerun adding them again to the users that were done
+    let mut temps = vec![];
+    for user in moved.keys() {
+        match faces.write_temp(user) {
+            Ok(tmp) => temps.push((user, tmp)),
+            Err(e) => {
+                for (_, tmp) in temps {
+                    let _ = std::fs::remove_file(tmp);
+                }
+                return Err(e);
+            }
+        }
+    }
+    for (user, tmp) in temps {
+        faces.replace(user, &tmp)?;
+    }
+    let mut done = from.as_os_str().to_owned();
+    done.push(".migrated");
+    std::fs::rename(from, &done).with_context(|| format!("couldn't rename {}", from.display()))?;
+    Ok(moved)
+}
+
+/// How close the models of one user are to a face
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserDistance {
+    pub user: String,
+    pub models: usize,
+    pub min: f64,
+    pub mean: f64,
+}
+
+/// How a face compares to the known faces
+#[derive(Debug, Clone)]
+pub struct MatchResult<'a> {
+    best: &'a ModelData,
+    distance: f64,
+    second_distance: Option<f64>,
+    users: Vec<UserDistance>,
+}
+
+impl<'a> MatchResult<'a> {
+    /// Build the result from the distance to each model with an owner.
+    ///
+    /// Only the models of `user` can be the best match, the others are still used for
+    /// the margin. `None` if there are no models of `user`.
+    fn new(
+        distances: impl Iterator<Item = (&'a ModelData, f64)>,
+        user: Option<&str>,
+    ) -> Option<Self> {
+        let mut best: Option<(&ModelData, f64)> = None;
+        let mut users: Vec<UserDistance> = vec![];
+        for (model, dist) in distances {
+            let Some(owner) = model.user() else {
+                continue;
+            };
+            if user.is_none_or(|u| u == owner) && best.is_none_or(|(_, d)| dist < d) {
+                best = Some((model, dist));
+            }
+            match users.iter_mut().find(|u| u.user == owner) {
+                Some(user) => {
+                    // running mean
+                    user.mean += (dist - user.mean) / (user.models + 1) as f64;
+                    user.models += 1;
+                    user.min = user.min.min(dist);
+                }
+                None => users.push(UserDistance {
+                    user: owner.to_owned(),
+                    models: 1,
+                    min: dist,
+                    mean: dist,
+                }),
+            }
+        }
+        let (best, distance) = best?;
+        users.sort_by(|a, b| a.min.total_cmp(&b.min));
+        let second_distance = users
             .iter()
-            .find(|known| known.encoding().distance(encoding) <= threshold)
-            .inspect(|v| {
-                log::trace!(target: "enc_match",
-                    "Matched: {} with distance {}",
-                    v.label,
-                    v.encoding().distance(encoding)
+            .find(|u| best.user() != Some(&u.user))
+            .map(|u| u.min);
+        Some(Self {
+            best,
+            distance,
+            second_distance,
+            users,
+        })
+    }
+
+    /// The closest model
+    pub fn model(&self) -> &'a ModelData {
+        self.best
+    }
+
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    /// Distance to the closest model of any other user.
+    ///
+    /// Other models of the same user being close doesn't make the match ambiguous.
+    pub fn second_distance(&self) -> Option<f64> {
+        self.second_distance
+    }
+
+    /// Distances for each user, closest first
+    pub fn users(&self) -> &[UserDistance] {
+        &self.users
+    }
+
+    /// Whether the closest model is within `threshold`, and at least `margin` closer
+    /// than any other user's. Another user being closer is never a match.
+    pub fn is_match(&self, threshold: f64, margin: f64) -> bool {
+        self.distance <= threshold
+            && self
+                .second_distance
+                .is_none_or(|d| d - self.distance >= margin)
+    }
+}
+
+impl std::fmt::Display for MatchResult<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let user = self.best.user().unwrap_or_default();
+        write!(
+            f,
+            "{} of {user} at distance {:.3}",
+            self.best.label, self.distance
+        )?;
+        if let Some(d) = self.second_distance {
+            write!(f, ", next closest user at {d:.3}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_match() {
+        let model = |id, user: &str| {
+            let enc = FaceEncoding::from_vec(&vec![0.0; 128]).unwrap();
+            let label = format!("Model #{id}");
+            ModelData::new(SystemTime::UNIX_EPOCH, user.to_owned(), label, id, enc)
+        };
+        let models = [model(1, "alice"), model(2, "bob"), model(3, "alice")];
+        let distances = || models.iter().zip([0.5, 0.55, 0.3]);
+        let res = MatchResult::new(distances(), None).unwrap();
+        assert_eq!(res.model().id, 3);
+        assert_eq!(res.distance(), 0.3);
+        assert_eq!(res.second_distance(), Some(0.55));
+        assert_eq!(
+            res.users(),
+            [
+                UserDistance {
+                    user: "alice".into(),
+                    models: 2,
+                    min: 0.3,
+                    mean: 0.4,
+                },
+                UserDistance {
+                    user: "bob".into(),
+                    models: 1,
+                    min: 0.55,
+                    mean: 0.55,
+                },
+            ]
+        );
+        assert!(res.is_match(0.6, 0.2));
+        assert!(!res.is_match(0.6, 0.3));
+        assert!(!res.is_match(0.2, 0.0));
+        assert_eq!(
+            MatchResult::new(distances(), Some("alice"))
+                .unwrap()
+                .model()
+                .id,
+            3
+        );
+
+        // only bob's model can match, but alice's are closer
+        let res = MatchResult::new(distances(), Some("bob")).unwrap();
+        assert_eq!(res.model().id, 2);
+        assert_eq!(res.second_distance(), Some(0.3));
+        assert!(!res.is_match(0.6, 0.0));
+
+        assert!(MatchResult::new(distances(), Some("eve")).is_none());
+        assert!(MatchResult::new(std::iter::empty(), None).is_none());
+    }
+
+    #[test]
+    fn per_user_files() {
+        let dir = crate::utils::temp_dir("models");
+        let models_dir = dir.join("models");
+        let enc = || FaceEncoding::from_vec(&vec![0.0; 128]).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let mut faces = Faces::from_dir(&models_dir).unwrap();
+        assert_eq!(mode(&models_dir), 0o700);
+        faces.add_face(enc(), "alice".into(), None).unwrap();
+        faces.save("alice").unwrap();
+        assert_eq!(mode(&models_dir.join("alice.json")), 0o600);
+        assert!(faces.save("../alice").is_err());
+
+        // an old faces file, with one face from before users were tracked
+        let old = dir.join("faces.json");
+        let old_faces = [
+            ModelData::new(SystemTime::UNIX_EPOCH, "bob".into(), "bob".into(), 1, enc()),
+            ModelData {
+                user: None,
+                ..ModelData::new(
+                    SystemTime::UNIX_EPOCH,
+                    String::new(),
+                    "alice".into(),
+                    2,
+                    enc(),
                 )
-            })
+            },
+        ];
+        let json = json!(old_faces.iter().map(ModelData::as_json).collect::<Vec<_>>());
+        std::fs::write(&old, json.to_string()).unwrap();
+        assert!(migrate(&old, &models_dir, None).is_err());
+        let moved = migrate(&old, &models_dir, Some("alice")).unwrap();
+        assert_eq!(moved, [("alice".into(), 1), ("bob".into(), 1)].into());
+        assert!(!old.exists());
+
+        let faces = Faces::from_dir(&models_dir).unwrap();
+        assert!(!faces.is_empty("bob"));
+        assert!(faces.is_empty("eve"));
+        let alice = &faces.users["alice"];
+        assert_eq!(alice.iter().map(|m| m.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(alice[1].label(), "alice");
+
+        // a file that others can change is skipped, and never overwritten
+        let carol = models_dir.join("carol.json");
+        std::fs::write(&carol, "[]").unwrap();
+        std::fs::set_permissions(&carol, Permissions::from_mode(0o666)).unwrap();
+        let mut faces = Faces::from_dir(&models_dir).unwrap();
+        assert!(faces.is_empty("carol"));
+        faces.add_face(enc(), "carol".into(), None).unwrap();
+        assert!(faces.save("carol").is_err());
+        assert_eq!(std::fs::read_to_string(&carol).unwrap(), "[]");
+
+        // faces readable by others are skipped too
+        std::fs::set_permissions(&carol, Permissions::from_mode(0o644)).unwrap();
+        assert!(Faces::from_dir(&models_dir)
+            .unwrap()
+            .skipped
+            .contains("carol"));
+
+        // nothing is migrated if one of the users can't be written
+        let model =
+            |user: &str| ModelData::new(SystemTime::UNIX_EPOCH, user.into(), user.into(), 1, enc());
+        let json = json!([model("bob").as_json(), model("carol").as_json()]);
+        std::fs::write(&old, json.to_string()).unwrap();
+        assert!(migrate(&old, &models_dir, None).is_err());
+        assert!(old.exists());
+        assert!(!models_dir.join("bob.json.tmp").exists());
+        assert_eq!(Faces::from_dir(&models_dir).unwrap().users["bob"].len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
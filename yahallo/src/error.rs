@@ -12,8 +12,36 @@ pub enum Error {
     TooDark,
     #[error("Unknown user!")]
     UnknownUser,
+    #[error("Liveness check failed!")]
+    LivenessFailed,
+    #[error("Camera is busy!")]
+    CameraBusy,
+    #[error("Camera not found!")]
+    CameraMissing,
+    #[error("Camera error: {0}")]
+    CameraIoError(String),
     #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// Whether the camera could not be used, as opposed to a failed match
+    pub fn is_camera_error(&self) -> bool {
+        matches!(
+            self,
+            Error::CameraBusy | Error::CameraMissing | Error::CameraIoError(_)
+        )
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        // keep our own errors, even if context was added on the way
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Other(e),
+        }
+    }
 }
 
 /// Wrapper that can be sent over dbus.
@@ -46,6 +74,10 @@ impl dbus::arg::Append for DbusResult {
                 Error::MultipleFaces => i.append("MultipleFaces"),
                 Error::TooDark => i.append("TooDark"),
                 Error::UnknownUser => i.append("UnknownUser"),
+                Error::LivenessFailed => i.append("LivenessFailed"),
+                Error::CameraBusy => i.append("CameraBusy"),
+                Error::CameraMissing => i.append("CameraMissing"),
+                Error::CameraIoError(e) => i.append(format!("CameraIoError: {e}")),
                 Error::Other(e) => i.append(e.to_string()),
             },
         }
@@ -63,7 +95,15 @@ impl<'a> dbus::arg::Get<'a> for DbusResult {
             "MultipleFaces" => Some(DbusResult::Error(Error::MultipleFaces)),
             "TooDark" => Some(DbusResult::Error(Error::TooDark)),
             "UnknownUser" => Some(DbusResult::Error(Error::UnknownUser)),
-            _ => Some(DbusResult::Error(Error::Other(anyhow::anyhow!(s)))),
+            "LivenessFailed" => Some(DbusResult::Error(Error::LivenessFailed)),
+            "CameraBusy" => Some(DbusResult::Error(Error::CameraBusy)),
+            "CameraMissing" => Some(DbusResult::Error(Error::CameraMissing)),
+            s => match s.strip_prefix("CameraIoError: ") {
+                Some(e) => Some(DbusResult::Error(Error::CameraIoError(e.to_owned()))),
+                None => Some(DbusResult::Error(Error::Other(anyhow::anyhow!(
+                    s.to_owned()
+                )))),
+            },
         }
     }
 }
@@ -1,7 +1,6 @@
-use std::path::Path;
-
 use anyhow::Result;
-use data::{Faces, ModelData};
+use data::Faces;
+pub use data::MatchResult;
 use dlib_face_recognition::{
     FaceDetector, FaceDetectorTrait, FaceEncoderNetwork, FaceEncoderTrait, FaceEncoding,
     FaceEncodings, FaceLocations, LandmarkPredictor, LandmarkPredictorTrait,
@@ -11,19 +10,34 @@ use image::buffer::ConvertBuffer;
 use image::GenericImageView;
 use image::Luma;
 use image::RgbImage;
-use log::warn;
-use rscam::Frame;
+use log::{debug, warn};
 
 pub mod camera;
 pub mod config;
+pub mod consensus;
 pub mod data;
 mod error;
+pub mod liveness;
+pub mod spoof;
 mod utils;
 
-use crate::config::Config;
+use crate::camera::Frame;
+use crate::config::{Config, FaceSelection};
 pub use crate::error::{DbusResult, Error, YahalloResult};
 pub use crate::utils::Stopwatch;
 
+/// dlib model used to predict the landmarks of a face
+const LANDMARK_MODEL: &str = "shape_predictor_5_face_landmarks.dat";
+/// dlib model used to generate the encoding of a face
+const ENCODER_MODEL: &str = "dlib_face_recognition_resnet_model_v1.dat";
+/// All the dlib models needed in `Config::dlib_model_dir`
+pub(crate) const DLIB_MODELS: &[&str] = &[LANDMARK_MODEL, ENCODER_MODEL];
+/// dlib model with the eye landmarks, only needed for `blink_liveness`
+pub(crate) const BLINK_MODEL: &str = "shape_predictor_68_face_landmarks.dat";
+/// How far past `match_threshold` the face of the user may drift, like while the eyes are
+/// closed, and still count as theirs for [`FaceRecognizer::could_be_user`]
+const CLOSED_EYES_SLACK: f64 = 0.1;
+
 struct FaceDet(Box<dyn FaceDetectorTrait>);
 
 impl FaceDetectorTrait for FaceDet {
@@ -38,15 +52,17 @@ pub struct FaceRecognizer {
     fdet: FaceDet,
     lm_pred: LandmarkPredictor,
     encoder: FaceEncoderNetwork,
+    /// Only loaded when `blink_liveness` is on
+    blink_pred: Option<LandmarkPredictor>,
     known_faces: Faces,
 }
 
 impl FaceRecognizer {
     pub fn new(config: &Config) -> Result<Self> {
         let fdt = std::thread::spawn(FaceDetector::new);
-        let lm_path = config.dlib_model_dat("shape_predictor_5_face_landmarks.dat")?;
+        let lm_path = config.dlib_model_dat(LANDMARK_MODEL)?;
         let lmt = std::thread::spawn(move || LandmarkPredictor::open(lm_path));
-        let enc_path = config.dlib_model_dat("dlib_face_recognition_resnet_model_v1.dat")?;
+        let enc_path = config.dlib_model_dat(ENCODER_MODEL)?;
         let ent = std::thread::spawn(move || FaceEncoderNetwork::open(enc_path));
         let fdet = fdt
             .join()
@@ -60,32 +76,56 @@ impl FaceRecognizer {
             .join()
             .map_err(|_| anyhow::format_err!("Enc init failed!"))?
             .map_err(|e| anyhow::anyhow!(e))?;
+        let blink_pred = if config.blink_liveness() {
+            let path = config.dlib_model_dat(BLINK_MODEL)?;
+            Some(LandmarkPredictor::open(path).map_err(|e| anyhow::anyhow!(e))?)
+        } else {
+            None
+        };
 
-        let faces_file = config.faces_file();
-        let encs = Faces::from_file(faces_file)?;
+        let encs = Faces::from_dir(config.models_dir())?;
+        if config.faces_file().exists() {
+            warn!(
+                "{} is no longer used, move its faces with `yahallo migrate`",
+                config.faces_file().display()
+            );
+        }
         Ok(Self {
             fdet: FaceDet(Box::new(fdet)),
             lm_pred,
             encoder,
+            blink_pred,
             known_faces: encs,
         })
     }
 
-    /// Returns largest face rect on image, if it is available
-    pub fn get_face_rect(&self, matrix: &ImageMatrix) -> YahalloResult<Option<Rectangle>> {
-        // TODO: Actually return the largest :P
-        let locs = self.fdet.face_locations(matrix);
-        if locs.len() > 1 {
-            warn!("Expected just one face, found {}", locs.len());
-            return Err(Error::MultipleFaces);
-        }
-        Ok(locs.first().cloned())
+    /// Returns the face to use from the image as per `face_selection`, if there is one
+    pub fn get_face_rect(
+        &self,
+        image: &DlibImage,
+        config: &Config,
+    ) -> YahalloResult<Option<Rectangle>> {
+        Ok(self.get_face_rects(image, config)?.into_iter().next())
     }
 
-    pub fn gen_encodings(&self, matrix: &ImageMatrix) -> YahalloResult<FaceEncodings> {
-        let rect = &self.get_face_rect(matrix)?.ok_or(Error::NoFace)?;
-        let landmarks = self.lm_pred.face_landmarks(matrix, rect);
-        let encodings = self.encoder.get_face_encodings(matrix, &[landmarks], 0);
+    /// Returns the faces to try from the image as per `face_selection`, in order
+    pub fn get_face_rects(
+        &self,
+        image: &DlibImage,
+        config: &Config,
+    ) -> YahalloResult<Vec<Rectangle>> {
+        let locs = self.fdet.face_locations(image);
+        select_faces(&locs, image.size(), config.face_selection())
+    }
+
+    pub fn gen_encodings(
+        &self,
+        image: &DlibImage,
+        config: &Config,
+    ) -> YahalloResult<FaceEncodings> {
+        let rect = &self.get_face_rect(image, config)?.ok_or(Error::NoFace)?;
+        let landmarks = self.lm_pred.face_landmarks(image, rect);
+        let encodings = self.encoder.get_face_encodings(image, &[landmarks], 0);
         Ok(encodings)
     }
 
@@ -94,38 +134,86 @@ impl FaceRecognizer {
         self.encoder.get_face_encodings(matrix, &[landmarks], 0)
     }
 
-    /// Given an encoding, try to find the closest match
-    pub fn get_enc_info(&self, encoding: &FaceEncoding, config: &Config) -> Option<&ModelData> {
-        // TODO: For now, we only find the first match below threshold
-        self.known_faces
-            .check_match(encoding, config.match_threshold)
+    /// Given an encoding, find the closest known face of `user`, or of anyone if `None`
+    pub fn get_enc_info(
+        &self,
+        encoding: &FaceEncoding,
+        user: Option<&str>,
+    ) -> Option<MatchResult<'_>> {
+        self.known_faces.check_match(encoding, user)
+    }
+
+    /// Whether the result counts as a match, as per `match_threshold` and `match_margin`
+    pub fn is_match(result: &MatchResult, config: &Config) -> bool {
+        result.is_match(config.match_threshold, config.match_margin())
+    }
+
+    /// Whether the face could still be the user's, even if it doesn't match, like while the
+    /// eyes are closed. No other user's models may be closer.
+    pub fn could_be_user(result: &MatchResult, config: &Config) -> bool {
+        result.is_match(config.match_threshold + CLOSED_EYES_SLACK, 0.0)
     }
 
+    /// Compare the faces in the image against the known faces of `user`.
+    ///
+    /// Returns the first matching face, or the closest one if none match, along with
+    /// where it is. `None` if there is no face in the image, or no known faces of `user`.
     pub fn check_match(
         &self,
-        matrix: &ImageMatrix,
+        image: &DlibImage,
         config: &Config,
-    ) -> YahalloResult<Option<&ModelData>> {
+        user: &str,
+    ) -> YahalloResult<Option<(Rectangle, MatchResult<'_>)>> {
         // TODO: Check staleness of self.known_faces
-        let Some(rect) = self.get_face_rect(matrix)? else {
-            return Ok(None);
-        };
-        let encodings = self.gen_encodings_with_rect(matrix, &rect);
-        let encoding = encodings.first().unwrap();
-        // TODO: Return more info about the match
-        Ok(self.get_enc_info(encoding, config))
+        let mut closest: Option<(Rectangle, MatchResult)> = None;
+        for rect in self.get_face_rects(image, config)? {
+            let encodings = self.gen_encodings_with_rect(image, &rect);
+            let encoding = encodings.first().unwrap();
+            let Some(res) = self.get_enc_info(encoding, Some(user)) else {
+                continue;
+            };
+            if Self::is_match(&res, config) {
+                return Ok(Some((rect, res)));
+            }
+            if closest
+                .as_ref()
+                .is_none_or(|(_, c)| res.distance() < c.distance())
+            {
+                closest = Some((rect, res));
+            }
+        }
+        Ok(closest)
+    }
+
+    /// Mean eye aspect ratio of the face, if `blink_liveness` is on
+    pub fn eye_aspect_ratio(&self, image: &DlibImage, rect: &Rectangle) -> Option<f64> {
+        let pred = self.blink_pred.as_ref()?;
+        liveness::mean_eye_aspect_ratio(&pred.face_landmarks(image, rect))
     }
 
-    pub fn add_face(&mut self, enc: FaceEncoding, label: Option<String>) -> Result<()> {
-        self.known_faces.add_face(enc, label)
+    /// Re-read the known faces from the models dir
+    pub fn reload_faces(&mut self, config: &Config) -> Result<()> {
+        self.known_faces = Faces::from_dir(config.models_dir())?;
+        Ok(())
     }
 
-    pub fn has_faces(&self) -> bool {
-        !self.known_faces.is_empty()
+    pub fn add_face(
+        &mut self,
+        enc: FaceEncoding,
+        user: String,
+        label: Option<String>,
+    ) -> Result<()> {
+        self.known_faces.add_face(enc, user, label)
     }
 
-    pub fn dump_faces_file(&self, path: &Path) -> Result<()> {
-        self.known_faces.to_file(path)
+    /// Whether any faces were enrolled for the user
+    pub fn has_faces(&self, user: &str) -> bool {
+        !self.known_faces.is_empty(user)
+    }
+
+    /// Write the faces of the user to the models dir
+    pub fn save_faces(&self, user: &str) -> Result<()> {
+        self.known_faces.save(user)
     }
 }
 
@@ -143,24 +231,32 @@ impl FaceRecognizer {
 
 type GrayFrameImage = image::ImageBuffer<image::Luma<u8>, Frame>;
 
+/// The largest square in the middle of the image
 pub fn center_crop(
     img: &impl GenericImageView<Pixel = Luma<u8>>,
 ) -> image::SubImage<&impl GenericImageView<Pixel = Luma<u8>>> {
     let (w, h) = img.dimensions();
-    assert!(w >= h, "potrait image not supported");
-    let x = (w - h) / 2;
-    let cropped = image::imageops::crop_imm(img, x, 0, h, h);
-    cropped
+    let side = w.min(h);
+    image::imageops::crop_imm(img, (w - side) / 2, (h - side) / 2, side, side)
 }
 
-/// Convert the frame into an image buffer
-pub fn process_image(frame: Frame) -> Result<GrayFrameImage> {
-    image::ImageBuffer::<image::Luma<u8>, _>::from_raw(
+/// Convert the frame into a grayscale image buffer, turned upright as per the config
+pub fn process_image(frame: Frame, config: &Config) -> Result<GrayFrameImage> {
+    let frame = camera::format::to_gray(frame)?;
+    let img = image::ImageBuffer::<image::Luma<u8>, _>::from_raw(
         frame.resolution.0,
         frame.resolution.1,
         frame,
     )
-    .ok_or(anyhow::anyhow!("no img from cam frame"))
+    .ok_or(anyhow::anyhow!("no img from cam frame"))?;
+    let transform = config.camera_transform();
+    if transform.is_identity() {
+        return Ok(img);
+    }
+    let img = transform.apply(&img);
+    let (w, h) = img.dimensions();
+    let frame = Frame::new((w, h), camera::format::GREY, img.into_raw());
+    image::ImageBuffer::from_raw(w, h, frame).ok_or(anyhow::anyhow!("no img from cam frame"))
 }
 
 pub fn to_rgb(img: &GrayFrameImage) -> RgbImage {
@@ -169,12 +265,23 @@ pub fn to_rgb(img: &GrayFrameImage) -> RgbImage {
 
 pub fn is_dark(img: &impl GenericImageView<Pixel = Luma<u8>>, threshold_percent: u32) -> bool {
     let cropped = center_crop(img);
-    let hist = gen_hist::<12>(cropped.inner());
+    let hist = gen_hist::<12>(cropped.pixels().map(|(_, _, p)| p));
     let total: u32 = hist.iter().sum();
+    if total == 0 {
+        // nothing to see in an empty frame
+        return true;
+    }
     let dark_percent = (hist[0] * 100) / total;
     dark_percent >= threshold_percent
 }
 
+/// Average pixel value, from 0 to 255
+pub fn mean_brightness(img: &impl GenericImageView<Pixel = Luma<u8>>) -> f64 {
+    let (w, h) = img.dimensions();
+    let total: u64 = img.pixels().map(|(_, _, p)| u64::from(p.0[0])).sum();
+    total as f64 / (w as f64 * h as f64).max(1.0)
+}
+
 /// Resize to target width preserving the aspect ratio
 pub fn resize_to_width(img: &RgbImage, target_width: u32) -> RgbImage {
     let w = img.width();
@@ -193,24 +300,147 @@ const fn int_ceil(a: usize, b: usize) -> usize {
     (a - 1) / b + 1
 }
 
-
 const fn bin<const BINS: usize>(val: u8) -> usize {
     let per_bin: u8 = int_ceil(u8::MAX as usize, BINS) as u8;
     (val / per_bin) as usize
 }
 
-/// Get the histogram from a grayscale image
-fn gen_hist<const BINS: usize>(img: &impl GenericImageView<Pixel = Luma<u8>>) -> [u32; BINS] {
+/// Get the histogram from the pixels of a grayscale image
+fn gen_hist<const BINS: usize>(pixels: impl Iterator<Item = Luma<u8>>) -> [u32; BINS] {
     let mut hist = [0; BINS];
 
-    for (_, _, p) in img.pixels() {
+    for p in pixels {
         let val = p.0[0];
         hist[bin::<BINS>(val)] += 1;
     }
     hist
 }
 
-pub fn img_to_dlib(img: &RgbImage) -> Result<ImageMatrix> {
+/// An image converted for dlib, along with its size
+pub struct DlibImage {
+    matrix: ImageMatrix,
+    size: (u32, u32),
+}
+
+impl DlibImage {
+    pub fn new(img: &RgbImage) -> Self {
+        Self {
+            matrix: ImageMatrix::from_image(img),
+            size: img.dimensions(),
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+}
+
+impl std::ops::Deref for DlibImage {
+    type Target = ImageMatrix;
+
+    fn deref(&self) -> &ImageMatrix {
+        &self.matrix
+    }
+}
+
+/// Scale a rect found on a resized image back to the original size
+pub fn scale_rect(rect: &Rectangle, scale: f64) -> Rectangle {
+    Rectangle {
+        left: (rect.left as f64 * scale) as i64,
+        top: (rect.top as f64 * scale) as i64,
+        right: (rect.right as f64 * scale) as i64,
+        bottom: (rect.bottom as f64 * scale) as i64,
+    }
+}
+
+pub fn img_to_dlib(img: &RgbImage) -> Result<DlibImage> {
     let img = &resize_to_width(img, 320);
-    Ok(ImageMatrix::from_image(img))
+    Ok(DlibImage::new(img))
+}
+
+/// Pick the faces to try from those found in an image of `size`, in order
+fn select_faces(
+    faces: &[Rectangle],
+    (w, h): (u32, u32),
+    policy: FaceSelection,
+) -> YahalloResult<Vec<Rectangle>> {
+    if faces.len() > 1 {
+        debug!(
+            "Found {} faces, picking by {}",
+            faces.len(),
+            policy.as_str()
+        );
+    }
+    let area = |r: &Rectangle| (r.right - r.left) * (r.bottom - r.top);
+    // doubled, to stay in integers
+    let center_dist = |r: &Rectangle| {
+        let dx = r.left + r.right - i64::from(w);
+        let dy = r.top + r.bottom - i64::from(h);
+        dx * dx + dy * dy
+    };
+    let mut faces = faces.to_vec();
+    match policy {
+        FaceSelection::Reject if faces.len() > 1 => {
+            warn!("Expected just one face, found {}", faces.len());
+            return Err(Error::MultipleFaces);
+        }
+        FaceSelection::Reject => {}
+        FaceSelection::Largest => faces = faces.into_iter().max_by_key(area).into_iter().collect(),
+        FaceSelection::Central => {
+            faces = faces
+                .into_iter()
+                .min_by_key(center_dist)
+                .into_iter()
+                .collect()
+        }
+        FaceSelection::All => faces.sort_by_key(|r| std::cmp::Reverse(area(r))),
+    }
+    Ok(faces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn portrait_crop() {
+        let mut img = image::GrayImage::new(4, 8);
+        for y in 2..6 {
+            for x in 0..4 {
+                img.put_pixel(x, y, Luma([255]));
+            }
+        }
+        let cropped = center_crop(&img);
+        assert_eq!(cropped.dimensions(), (4, 4));
+        assert!(cropped.pixels().all(|(_, _, p)| p.0[0] == 255));
+        assert!(!is_dark(&img, 50));
+        assert!(is_dark(&image::GrayImage::new(0, 0), 50));
+    }
+
+    #[test]
+    fn face_selection() {
+        let rect = |left, top, side| Rectangle {
+            left,
+            top,
+            right: left + side,
+            bottom: top + side,
+        };
+        // a big face in the corner, and a smaller one in the middle
+        let faces = [rect(0, 0, 100), rect(140, 80, 40)];
+        let select = |policy| select_faces(&faces, (320, 200), policy);
+        assert_eq!(select(FaceSelection::Largest).unwrap(), [faces[0]]);
+        assert_eq!(select(FaceSelection::Central).unwrap(), [faces[1]]);
+        assert_eq!(select(FaceSelection::All).unwrap(), faces);
+        assert!(matches!(
+            select(FaceSelection::Reject),
+            Err(Error::MultipleFaces)
+        ));
+        assert_eq!(
+            select_faces(&faces[1..], (320, 200), FaceSelection::Reject).unwrap(),
+            [faces[1]]
+        );
+        assert!(select_faces(&[], (320, 200), FaceSelection::Largest)
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -0,0 +1,122 @@
+//! Telling a live face from a photo, by waiting for the eyes to blink.
+//!
+//! Uses the eye aspect ratio (EAR) from Soukupová and Čech, "Real-Time Eye Blink
+//! Detection using Facial Landmarks". It drops sharply when the eye closes.
+
+use dlib_face_recognition::Point;
+
+/// Landmarks of the eyes in the 68 point model, clockwise from the outer corner
+const RIGHT_EYE: std::ops::Range<usize> = 36..42;
+const LEFT_EYE: std::ops::Range<usize> = 42..48;
+
+fn dist(a: &Point, b: &Point) -> f64 {
+    ((a.x() - b.x()) as f64).hypot((a.y() - b.y()) as f64)
+}
+
+/// Height of the eye over its width
+fn eye_aspect_ratio(eye: &[Point]) -> f64 {
+    let vertical = dist(&eye[1], &eye[5]) + dist(&eye[2], &eye[4]);
+    let horizontal = dist(&eye[0], &eye[3]);
+    vertical / (2.0 * horizontal).max(f64::EPSILON)
+}
+
+/// Mean eye aspect ratio of both eyes, from the 68 landmarks of a face
+pub fn mean_eye_aspect_ratio(landmarks: &[Point]) -> Option<f64> {
+    if landmarks.len() < LEFT_EYE.end {
+        return None;
+    }
+    let right = eye_aspect_ratio(&landmarks[RIGHT_EYE]);
+    let left = eye_aspect_ratio(&landmarks[LEFT_EYE]);
+    Some((right + left) / 2.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Eyes {
+    /// Not seen open yet
+    Unknown,
+    Open,
+    Closed,
+    Blinked,
+}
+
+/// Looks for the eyes going from open, to closed, to open again
+#[derive(Debug, Clone)]
+pub struct BlinkDetector {
+    /// Eye aspect ratio at or below which the eyes count as closed
+    threshold: f64,
+    eyes: Eyes,
+}
+
+impl BlinkDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            eyes: Eyes::Unknown,
+        }
+    }
+
+    /// Record the eye aspect ratio of a frame. Returns whether a blink was seen so far.
+    pub fn push(&mut self, ear: f64) -> bool {
+        let open = ear > self.threshold;
+        self.eyes = match (self.eyes, open) {
+            (Eyes::Blinked, _) => Eyes::Blinked,
+            // eyes closed from the start, like in a photo
+            (Eyes::Unknown, false) => Eyes::Unknown,
+            (Eyes::Unknown | Eyes::Open, true) => Eyes::Open,
+            (Eyes::Open | Eyes::Closed, false) => Eyes::Closed,
+            (Eyes::Closed, true) => Eyes::Blinked,
+        };
+        self.blinked()
+    }
+
+    pub fn blinked(&self) -> bool {
+        self.eyes == Eyes::Blinked
+    }
+
+    /// Forget the eyes seen so far, when a frame didn't show the user's face.
+    pub fn reset(&mut self) {
+        self.eyes = Eyes::Unknown;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aspect_ratio() {
+        // 4 wide, 2 tall
+        let eye = [(0, 0), (1, -1), (3, -1), (4, 0), (3, 1), (1, 1)];
+        let mut landmarks = vec![Point::new(0, 0); 68];
+        for (i, &(x, y)) in eye.iter().enumerate() {
+            landmarks[RIGHT_EYE.start + i] = Point::new(x, y);
+            landmarks[LEFT_EYE.start + i] = Point::new(x + 10, y);
+        }
+        assert_eq!(mean_eye_aspect_ratio(&landmarks), Some(0.5));
+        assert_eq!(mean_eye_aspect_ratio(&landmarks[..5]), None);
+    }
+
+    #[test]
+    fn blink() {
+        let mut b = BlinkDetector::new(0.2);
+        // closed to open is not a blink
+        assert!(!b.push(0.1));
+        assert!(!b.push(0.3));
+        assert!(!b.push(0.15));
+        assert!(b.push(0.3));
+        assert!(b.push(0.1));
+    }
+
+    #[test]
+    fn reset() {
+        let mut b = BlinkDetector::new(0.2);
+        // someone else blinks, then a photo of the user shows up
+        assert!(!b.push(0.3));
+        assert!(!b.push(0.1));
+        assert!(b.push(0.3));
+        b.reset();
+        assert!(!b.blinked());
+        assert!(!b.push(0.3));
+        assert!(!b.push(0.3));
+    }
+}
@@ -0,0 +1,147 @@
+//! Telling real skin apart from a screen or a print, in IR frames.
+//!
+//! Screens don't emit in infrared, so a replay shows up near-black or as a flat patch.
+//! Real faces reflect the emitter's light, with varied brightness and fine texture.
+
+use dlib_face_recognition::Rectangle;
+use image::{GenericImageView, Luma};
+
+/// Number of histogram bins used for the entropy
+const BINS: usize = 32;
+
+/// What each feature is compared against, roughly where real faces start to look real.
+/// These were picked from a few IR cameras, and are only meant to scale the features.
+const MIN_MEAN: f64 = 32.0;
+const MIN_CONTRAST: f64 = 24.0;
+const MIN_TEXTURE: f64 = 6.0;
+
+/// Brightness and texture features of the face region
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpoofFeatures {
+    /// Mean brightness, from 0 to 255
+    pub mean: f64,
+    /// Standard deviation of the brightness
+    pub contrast: f64,
+    /// Entropy of the brightness histogram, in bits
+    pub entropy: f64,
+    /// Mean absolute difference between neighbouring pixels
+    pub texture: f64,
+}
+
+impl SpoofFeatures {
+    /// Extract the features from the face at `face`, in frame coordinates.
+    ///
+    /// `None` if the face is outside the frame.
+    pub fn from_face(
+        frame: &impl GenericImageView<Pixel = Luma<u8>>,
+        face: &Rectangle,
+    ) -> Option<Self> {
+        let (w, h) = frame.dimensions();
+        let left = face.left.clamp(0, w.into()) as u32;
+        let top = face.top.clamp(0, h.into()) as u32;
+        let right = face.right.clamp(0, w.into()) as u32;
+        let bottom = face.bottom.clamp(0, h.into()) as u32;
+        if right <= left + 1 || bottom <= top + 1 {
+            return None;
+        }
+        let px = |x, y| f64::from(frame.get_pixel(x, y).0[0]);
+
+        let mut hist = [0u32; BINS];
+        let (mut sum, mut sum_sq, mut diffs) = (0.0, 0.0, 0.0);
+        for y in top..bottom {
+            for x in left..right {
+                let v = px(x, y);
+                sum += v;
+                sum_sq += v * v;
+                hist[v as usize * BINS / 256] += 1;
+                if x + 1 < right && y + 1 < bottom {
+                    diffs += (v - px(x + 1, y)).abs() + (v - px(x, y + 1)).abs();
+                }
+            }
+        }
+        let n = f64::from((right - left) * (bottom - top));
+        let mean = sum / n;
+        let contrast = (sum_sq / n - mean * mean).max(0.0).sqrt();
+        let entropy = hist
+            .iter()
+            .filter(|&&c| c > 0)
+            .map(|&c| {
+                let p = f64::from(c) / n;
+                -p * p.log2()
+            })
+            .sum();
+        let texture = diffs / (2.0 * f64::from((right - left - 1) * (bottom - top - 1)));
+        Some(Self {
+            mean,
+            contrast,
+            entropy,
+            texture,
+        })
+    }
+
+    /// How much this looks like a real face, from 0 to 1.
+    ///
+    /// The geometric mean of the features, each scaled to 0-1, so that a single
+    /// missing feature (like a black screen, or a flat print) gives a low score.
+    pub fn score(&self) -> f64 {
+        let factors = [
+            (self.mean / MIN_MEAN).min(1.0),
+            (self.contrast / MIN_CONTRAST).min(1.0),
+            self.entropy / (BINS as f64).log2(),
+            (self.texture / MIN_TEXTURE).min(1.0),
+        ];
+        factors
+            .iter()
+            .product::<f64>()
+            .powf(1.0 / factors.len() as f64)
+    }
+}
+
+impl std::fmt::Display for SpoofFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "score {:.2} (mean {:.1}, contrast {:.1}, entropy {:.2}, texture {:.1})",
+            self.score(),
+            self.mean,
+            self.contrast,
+            self.entropy,
+            self.texture
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    #[test]
+    fn scores() {
+        let face = Rectangle {
+            left: 10,
+            top: 10,
+            right: 90,
+            bottom: 90,
+        };
+        let score = |img: &GrayImage| SpoofFeatures::from_face(img, &face).unwrap().score();
+        // a phone screen is black in IR, a print is flat
+        assert_eq!(score(&GrayImage::new(100, 100)), 0.0);
+        assert_eq!(score(&GrayImage::from_pixel(100, 100, Luma([120]))), 0.0);
+        // a bright, textured face
+        let mut seed = 1u32;
+        let real = GrayImage::from_fn(100, 100, |x, y| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            Luma([(60 + (x + y) + (seed >> 16) % 40) as u8])
+        });
+        assert!(score(&real) > 0.8, "{}", score(&real));
+
+        let outside = Rectangle {
+            left: 200,
+            top: 0,
+            right: 300,
+            bottom: 50,
+        };
+        assert!(SpoofFeatures::from_face(&real, &outside).is_none());
+    }
+}
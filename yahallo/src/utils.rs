@@ -28,3 +28,12 @@ impl Drop for Stopwatch {
         );
     }
 }
+
+/// A fresh directory for the files of the test `name`
+#[cfg(test)]
+pub(crate) fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("yahallo-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}